@@ -1,8 +1,15 @@
+use std::num::TryFromIntError;
+
+use num_derive::FromPrimitive;
 use thiserror::Error;
 
-use solana_program::program_error::ProgramError;
+use solana_program::{
+    decode_error::DecodeError,
+    msg,
+    program_error::{PrintProgramError, ProgramError},
+};
 
-#[derive(Error, Debug, Copy, Clone)]
+#[derive(Error, Debug, Copy, Clone, FromPrimitive)]
 pub enum EscrowError {
     /// Invalid instruction
     #[error("Invalid Instruction")]
@@ -16,6 +23,87 @@ pub enum EscrowError {
     /// Amount Overflow
     #[error("Amount Overflow")]
     AmountOverflow,
+    /// Incompatible Escrows
+    #[error("Incompatible Escrows")]
+    IncompatibleEscrows,
+    /// Invalid Freeze Authority
+    #[error("Invalid Freeze Authority")]
+    InvalidFreezeAuthority,
+    /// Below Reserve
+    #[error("Below Reserve")]
+    BelowReserve,
+    /// Withdrawer Must Be Wallet
+    #[error("Withdrawer Must Be Wallet")]
+    WithdrawerMustBeWallet,
+    /// Duplicate Escrow
+    #[error("Duplicate Escrow")]
+    DuplicateEscrow,
+    /// Zero Amount
+    #[error("Zero Amount")]
+    ZeroAmount,
+    /// Invalid Milestones
+    #[error("Invalid Milestones")]
+    InvalidMilestones,
+    /// Too Many Escrows
+    #[error("Too Many Escrows")]
+    TooManyEscrows,
+    /// Empty Temp Account
+    #[error("Empty Temp Account")]
+    EmptyTempAccount,
+    /// Token Account Frozen
+    #[error("Token Account Frozen")]
+    TokenAccountFrozen,
+    /// Too Many Entries
+    #[error("Too Many Entries")]
+    TooManyEntries,
+    /// Partial Not Allowed
+    #[error("Partial Not Allowed")]
+    PartialNotAllowed,
+    /// Invalid Authorization
+    #[error("Invalid Authorization")]
+    InvalidAuthorization,
+    /// State Assertion Failed
+    #[error("State Assertion Failed")]
+    StateAssertionFailed,
+    /// Withdraw Cooldown Active
+    #[error("Withdraw Cooldown Active")]
+    WithdrawCooldownActive,
+    /// Insufficient Escrow Balance
+    #[error("Insufficient Escrow Balance")]
+    InsufficientEscrowBalance,
+    /// Numeric Conversion
+    #[error("Numeric Conversion")]
+    NumericConversion,
+    /// Receive Account Owner Mismatch
+    #[error("Receive Account Owner Mismatch")]
+    ReceiveAccountOwnerMismatch,
+    /// Escrow Already Closed
+    #[error("Escrow Already Closed")]
+    EscrowAlreadyClosed,
+    /// Invalid Preimage
+    #[error("Invalid Preimage")]
+    InvalidPreimage,
+    /// Escrow Expired
+    #[error("Escrow Expired")]
+    EscrowExpired,
+    /// Escrow Not Expired
+    #[error("Escrow Not Expired")]
+    EscrowNotExpired,
+    /// Unexpected Lamports
+    #[error("Unexpected Lamports")]
+    UnexpectedLamports,
+    /// Escrow Sealed
+    #[error("Escrow Sealed")]
+    EscrowSealed,
+    /// Invalid Authority
+    #[error("Invalid Authority")]
+    InvalidAuthority,
+}
+
+impl From<TryFromIntError> for EscrowError {
+    fn from(_: TryFromIntError) -> Self {
+        EscrowError::NumericConversion
+    }
 }
 
 impl From<EscrowError> for ProgramError {
@@ -24,3 +112,18 @@ impl From<EscrowError> for ProgramError {
     }
 }
 
+
+impl<T> DecodeError<T> for EscrowError {
+    fn type_of() -> &'static str {
+        "EscrowError"
+    }
+}
+
+impl PrintProgramError for EscrowError {
+    fn print<E>(&self) {
+        #[cfg(feature = "compact-errors")]
+        msg!("Error: {}", *self as u32);
+        #[cfg(not(feature = "compact-errors"))]
+        msg!("Error: {}", self);
+    }
+}
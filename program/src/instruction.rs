@@ -1,7 +1,269 @@
-use solana_program::program_error::ProgramError;
-use std::convert::TryInto;
+use solana_program::{
+    bpf_loader_upgradeable,
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_program,
+};
+use std::convert::{TryFrom, TryInto};
 
-use crate::error::EscrowError::InvalidInstruction;
+use arrayref::array_ref;
+use spl_associated_token_account::get_associated_token_address;
+
+use crate::{
+    error::{EscrowError, EscrowError::InvalidInstruction},
+    state::{Milestone, MAX_MILESTONES},
+};
+
+/// Returns the tag byte of an escrow instruction without unpacking the rest of it
+pub fn peek_tag(input: &[u8]) -> Result<u8, ProgramError> {
+    input.first().copied().ok_or_else(|| InvalidInstruction.into())
+}
+
+/// The message an escrow's authority signs to let `taker` withdraw `amount` from
+/// `escrow_account`. `withdraw_nonce` is the escrow's current one, it moves on with every
+/// withdraw so the signature cannot be replayed.
+pub fn authorization_message(
+    escrow_account: &Pubkey,
+    taker: &Pubkey,
+    amount: u64,
+    withdraw_nonce: u64,
+) -> Vec<u8> {
+    [
+        escrow_account.as_ref(),
+        taker.as_ref(),
+        &amount.to_le_bytes(),
+        &withdraw_nonce.to_le_bytes(),
+    ]
+    .concat()
+}
+
+/// The leading byte of the instruction data, one per [EscrowInstruction](enum.EscrowInstruction.html) variant
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InstructionTag {
+    InitEscrow = 0,
+    Withdraw = 1,
+    SplitEscrow = 2,
+    MergeEscrow = 3,
+    Drain = 4,
+    Decline = 5,
+    CancelEscrow = 6,
+    AssertState = 7,
+    WithdrawSplit = 8,
+    StateHash = 9,
+    Seal = 10,
+    CancelPartial = 11,
+    WithdrawTreasury = 12,
+}
+
+impl TryFrom<u8> for InstructionTag {
+    type Error = EscrowError;
+
+    fn try_from(tag: u8) -> Result<Self, Self::Error> {
+        Ok(match tag {
+            0 => Self::InitEscrow,
+            1 => Self::Withdraw,
+            2 => Self::SplitEscrow,
+            3 => Self::MergeEscrow,
+            4 => Self::Drain,
+            5 => Self::Decline,
+            6 => Self::CancelEscrow,
+            7 => Self::AssertState,
+            8 => Self::WithdrawSplit,
+            9 => Self::StateHash,
+            10 => Self::Seal,
+            11 => Self::CancelPartial,
+            12 => Self::WithdrawTreasury,
+            _ => return Err(InvalidInstruction),
+        })
+    }
+}
+
+/// Parameters of [EscrowInstruction::InitEscrow](enum.EscrowInstruction.html#variant.InitEscrow)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InitEscrowParams {
+    /// The amount party A will allow B to withdraw
+    pub amount: u64,
+    /// Keep the temp token account with the initializer, approve the PDA as delegate for
+    /// `amount` and freeze it instead of moving its ownership to the PDA
+    pub freeze: bool,
+    /// The amount that must stay in the escrow, withdrawals may not go below it. Cannot be
+    /// combined with `full_only`, as a full withdraw would always take the reserve along
+    pub reserve_amount: u64,
+    /// Reject withdrawers that are off-curve (i.e. PDAs) rather than normal wallets
+    pub require_wallet_withdrawer: bool,
+    /// Record the escrow in a registry PDA keyed on (initializer, mint, withdrawer), failing if
+    /// one already exists
+    pub use_registry: bool,
+    /// Transfer `amount` from the initializer's token account into a vault token account the
+    /// program creates for this escrow at the PDA derived from `[b"vault", escrow]`, instead of
+    /// moving ownership of the initializer's account. Cannot be combined with `freeze`
+    pub vault: bool,
+    /// Number of entries of `milestones` in use, zero releases the whole deposit immediately
+    pub milestone_count: u8,
+    /// Release schedule, the fractions of the entries in use must add up to 10000 bps
+    pub milestones: [Milestone; MAX_MILESTONES],
+    /// Escrow `amount` lamports held by the escrow account itself instead of tokens. Cannot be
+    /// combined with `freeze`, `vault` or `use_registry`
+    pub lamports: bool,
+    /// Reject withdraws of less than the whole deposit, so only a full withdraw settles the
+    /// escrow
+    pub full_only: bool,
+    /// Key that must sign every withdraw through the ed25519 program, `Pubkey::default()` to
+    /// leave withdraws unrestricted
+    pub authority: Pubkey,
+    /// Remainders below this many tokens are swept to `dust_collector` on `Decline` and
+    /// `CancelEscrow`, `0` to disable. Cannot be combined with `freeze` or `lamports`
+    pub dust_threshold: u64,
+    /// Minimum number of seconds between two withdraws, `0` for no cooldown
+    pub withdraw_cooldown_secs: u32,
+    /// Send the escrow's rent to the treasury PDA derived from `[b"treasury"]` when it closes.
+    /// The program's upgrade authority can take it out with `WithdrawTreasury`.
+    /// Cannot be combined with `lamports`
+    pub rent_to_treasury: bool,
+    /// Only let withdraws pay into token accounts owned by the withdrawer. Has no effect with
+    /// `lamports`, which always pay the withdrawer itself
+    pub require_receive_owner: bool,
+    /// SHA-256 hash of the preimage every withdraw has to present, all zeroes for none.
+    /// Independent of `expiry_unix_timestamp`, without an expiry it is a plain shared-secret
+    /// gate the initializer can never reclaim around
+    pub hashlock: [u8; 32],
+    /// Withdraws stop and the initializer may cancel from this time on, `0` for no expiry.
+    /// Escrows with an expiry can only be cancelled once it passed
+    pub expiry_unix_timestamp: i64,
+    /// Reject escrow accounts holding more lamports than their rent-exempt minimum, so nothing
+    /// pre-funded ends up swept along with the escrow
+    pub require_exact_rent: bool,
+    /// Off-chain order id stored on the escrow and echoed in return data, purely informational
+    pub reference_id: u64,
+    /// Withdraws pay into the taker's associated token account and create it when it does not
+    /// exist yet. Cannot be combined with `lamports`
+    pub create_receive_account: bool,
+    /// Token account of the escrowed mint the sub-threshold remainders go to, required with a
+    /// `dust_threshold` and `Pubkey::default()` otherwise
+    pub dust_collector: Pubkey,
+}
+
+impl InitEscrowParams {
+    pub const LEN: usize = 151 + Milestone::LEN * MAX_MILESTONES;
+
+    /// Parameters escrowing `amount` tokens with every option off, what the original InitEscrow
+    /// carrying only the amount asked for
+    pub fn new(amount: u64) -> Self {
+        Self {
+            amount,
+            freeze: false,
+            reserve_amount: 0,
+            require_wallet_withdrawer: false,
+            use_registry: false,
+            vault: false,
+            milestone_count: 0,
+            milestones: Default::default(),
+            lamports: false,
+            full_only: false,
+            authority: Pubkey::default(),
+            dust_threshold: 0,
+            withdraw_cooldown_secs: 0,
+            rent_to_treasury: false,
+            require_receive_owner: false,
+            hashlock: [0; 32],
+            expiry_unix_timestamp: 0,
+            require_exact_rent: false,
+            reference_id: 0,
+            create_receive_account: false,
+            dust_collector: Pubkey::default(),
+        }
+    }
+
+    /// Decodes the bytes following the instruction tag, rejecting buffers of the wrong length,
+    /// a reserve larger than the escrowed amount and conflicting modes. A lone 8-byte amount,
+    /// as clients predating the parameters send it, decodes to [new](#method.new).
+    pub fn from_bytes(input: &[u8]) -> Result<Self, ProgramError> {
+        if input.len() == 8 {
+            return Ok(Self::new(EscrowInstruction::unpack_amount(input)?));
+        }
+        if input.len() != Self::LEN {
+            return Err(InvalidInstruction.into());
+        }
+
+        let mut params = Self {
+            amount: EscrowInstruction::unpack_amount(&input[..8])?,
+            freeze: EscrowInstruction::unpack_flag(&input[8..9])?,
+            reserve_amount: EscrowInstruction::unpack_amount(&input[9..17])?,
+            require_wallet_withdrawer: EscrowInstruction::unpack_flag(&input[17..18])?,
+            use_registry: EscrowInstruction::unpack_flag(&input[18..19])?,
+            vault: EscrowInstruction::unpack_flag(&input[19..20])?,
+            milestone_count: input[20],
+            milestones: Default::default(),
+            lamports: EscrowInstruction::unpack_flag(&input[61..62])?,
+            full_only: EscrowInstruction::unpack_flag(&input[62..63])?,
+            authority: Pubkey::new_from_array(*array_ref![input, 63, 32]),
+            dust_threshold: EscrowInstruction::unpack_amount(&input[95..103])?,
+            withdraw_cooldown_secs: u32::from_le_bytes(*array_ref![input, 103, 4]),
+            rent_to_treasury: EscrowInstruction::unpack_flag(&input[107..108])?,
+            require_receive_owner: EscrowInstruction::unpack_flag(&input[108..109])?,
+            hashlock: *array_ref![input, 109, 32],
+            expiry_unix_timestamp: i64::from_le_bytes(*array_ref![input, 141, 8]),
+            require_exact_rent: EscrowInstruction::unpack_flag(&input[149..150])?,
+            reference_id: EscrowInstruction::unpack_amount(&input[150..158])?,
+            create_receive_account: EscrowInstruction::unpack_flag(&input[158..159])?,
+            dust_collector: Pubkey::new_from_array(*array_ref![input, 159, 32]),
+        };
+        for (i, milestone) in params.milestones.iter_mut().enumerate() {
+            *milestone =
+                Milestone::unpack_from_slice(array_ref![input, 21 + i * Milestone::LEN, Milestone::LEN]);
+        }
+
+        if params.reserve_amount > params.amount
+            || (params.reserve_amount > 0 && params.full_only)
+            || (params.freeze && params.vault)
+            || (params.lamports && (params.freeze || params.vault || params.use_registry))
+            || (params.dust_threshold > 0 && (params.freeze || params.lamports))
+            || ((params.dust_threshold > 0) != (params.dust_collector != Pubkey::default()))
+            || (params.rent_to_treasury && params.lamports)
+            || (params.create_receive_account && params.lamports)
+        {
+            return Err(InvalidInstruction.into());
+        }
+
+        if params.milestone_count as usize > MAX_MILESTONES {
+            return Err(EscrowError::TooManyEntries.into());
+        }
+
+        Ok(params)
+    }
+
+    /// Encodes the parameters as they follow the instruction tag.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::LEN);
+        buf.extend_from_slice(&self.amount.to_le_bytes());
+        buf.push(self.freeze as u8);
+        buf.extend_from_slice(&self.reserve_amount.to_le_bytes());
+        buf.push(self.require_wallet_withdrawer as u8);
+        buf.push(self.use_registry as u8);
+        buf.push(self.vault as u8);
+        buf.push(self.milestone_count);
+        for milestone in self.milestones.iter() {
+            let mut dst = [0u8; Milestone::LEN];
+            milestone.pack_into_slice(&mut dst);
+            buf.extend_from_slice(&dst);
+        }
+        buf.push(self.lamports as u8);
+        buf.push(self.full_only as u8);
+        buf.extend_from_slice(self.authority.as_ref());
+        buf.extend_from_slice(&self.dust_threshold.to_le_bytes());
+        buf.extend_from_slice(&self.withdraw_cooldown_secs.to_le_bytes());
+        buf.push(self.rent_to_treasury as u8);
+        buf.push(self.require_receive_owner as u8);
+        buf.extend_from_slice(&self.hashlock);
+        buf.extend_from_slice(&self.expiry_unix_timestamp.to_le_bytes());
+        buf.push(self.require_exact_rent as u8);
+        buf.extend_from_slice(&self.reference_id.to_le_bytes());
+        buf.push(self.create_receive_account as u8);
+        buf.extend_from_slice(self.dust_collector.as_ref());
+        buf
+    }
+}
 
 pub enum EscrowInstruction {
     /// Starts the trade by creating and populating an escrow account and transferring ownership
@@ -9,17 +271,59 @@ pub enum EscrowInstruction {
     ///
     /// Accounts expected:
     ///
-    /// 0. `[signer]` The account of the person initializing the escrow
-    /// 1. `[writable]` Temporary token account that should be created prior to this instruction 
-    /// and owned by the initializer
+    /// 0. `[signer]` The account of the person initializing the escrow, also `[writable]` when
+    ///    `use_registry` or `vault` is set as it pays for the registry entry and the vault
+    /// 1. `[writable]` Temporary token account that should be created prior to this instruction
+    ///    and owned by the initializer. With `vault` set, the initializer's token account to
+    ///    fund the vault from
     /// 2. `[]` The withdrawer's pubkey
-    /// 3. `[writable]` The escrow account, it will hold all necessary info about the trade.
-    /// 4. `[]` The rent sysvar
-    /// 5. `[]` The token program
-    InitEscrow {
-        /// The amount party A will allow B to withdraw
-        amount: u64,
-    },
+    /// 3. `[writable]` The escrow account, it will hold all necessary info about the trade. It
+    ///    must be `Escrow::LEN` bytes long
+    /// 4. `[]` The token program
+    ///
+    /// When `freeze` is set, these follow the accounts above:
+    ///
+    /// 0. `[]` The temp token account's mint, its freeze authority must be the PDA
+    /// 1. `[]` The PDA account
+    ///
+    /// When `vault` is set, these follow the accounts above instead:
+    ///
+    /// 0. `[writable]` The vault, the PDA derived from `[b"vault", escrow]`. It is created as a
+    ///    token account owned by the PDA, so it cannot exist yet
+    /// 1. `[]` The mint of account 1
+    /// 2. `[]` The system program
+    ///
+    /// When `use_registry` is set, these come last:
+    ///
+    /// 0. `[writable]` The registry PDA derived from
+    ///    `[b"registry", initializer, mint, withdrawer]`, must not hold an entry yet. The entry is
+    ///    closed again together with the escrow
+    /// 1. `[]` The system program
+    ///
+    /// With the `escrow-limit` feature, these come last:
+    ///
+    /// 0. `[writable]` The initializer's escrow counter PDA derived from `[b"counter", initializer]`
+    /// 1. `[]` The system program
+    ///
+    /// When `lamports` is set, the accounts expected are instead:
+    ///
+    /// 0. `[signer, writable]` The account of the person initializing the escrow, pays `amount`
+    /// 1. `[]` The withdrawer's pubkey
+    /// 2. `[writable]` The escrow account, it will hold the escrowed lamports on top of its rent
+    /// 3. `[]` The system program
+    /// 4. `[writable]` The initializer's escrow counter PDA, only with the `escrow-limit` feature
+    ///
+    /// The rent is read through `Rent::get`, no rent sysvar account is expected.
+    ///
+    /// On success a single `ESCROW_CREATED pubkey=<escrow> mint=<mint> withdrawer=<withdrawer>
+    /// amount=<amount>` line is logged, lamport escrows report the native mint.
+    ///
+    /// Return data: the 32 bytes of the escrow account pubkey followed by the little-endian
+    /// `reference_id`
+    ///
+    /// The data may also be just the 8-byte amount, which initializes a plain token escrow with
+    /// every option off.
+    InitEscrow(InitEscrowParams),
     /// Withdraw
     ///
     /// Accounts expected:
@@ -31,28 +335,365 @@ pub enum EscrowInstruction {
     /// 4. `[writable]` The escrow account holding the escrow info
     /// 5. `[]` The token program
     /// 6. `[]` The PDA account
+    /// 7. `[]` The temp token account's mint, only for escrows initialized with `freeze`
+    /// 8. `[writable]` The initializer's escrow counter PDA, only with the `escrow-limit` feature.
+    ///    Follows the PDA account directly when there is no mint
+    /// 9. `[writable]` The treasury PDA, only for escrows initialized with `rent_to_treasury` and
+    ///    only read when the withdraw closes the escrow
+    /// 10. `[writable]` The registry entry, only for escrows initialized with `use_registry` and
+    ///     only read when the withdraw closes the escrow
+    ///
+    /// For escrows initialized with `create_receive_account`, account 1 must be the taker's
+    /// associated token account and is created when missing, with account 0 `[writable]` to pay
+    /// for it. These then follow the mint, or the PDA account when there is none, ahead of the
+    /// escrow counter:
+    ///
+    /// 0. `[]` The mint of the escrowed tokens
+    /// 1. `[]` The system program
+    /// 2. `[]` The associated token account program
+    ///
+    /// For escrows initialized with `lamports`, the accounts expected are instead:
+    ///
+    /// 0. `[signer, writable]` The account of the person taking the trade, receives the lamports
+    /// 1. `[writable]` The escrow account holding the escrow info and lamports
+    /// 2. `[writable]` The initializer's main account to send their rent fees to
+    /// 3. `[writable]` The initializer's escrow counter PDA, only with the `escrow-limit` feature
+    ///
+    /// When the escrow has an authority, the instructions sysvar must be passed as well and the
+    /// instruction right before this one must be an ed25519 program instruction verifying the
+    /// authority's signature over [authorization_message](fn.authorization_message.html) with the
+    /// escrow's current `withdraw_nonce`.
+    ///
+    /// The clock sysvar may be passed as the very last account, otherwise it is read through
+    /// `Clock::get`.
+    ///
+    /// Return data: a byte that is `1` if the withdraw emptied and closed the escrow and `0`
+    /// otherwise, followed by the escrow's little-endian `reference_id` and its
+    /// [EscrowStatus](../state/enum.EscrowStatus.html) as a byte
     Withdraw {
         /// The amount withdrawer wants to withdraw
         amount: u64,
-    }
+        /// The preimage of the escrow's hashlock, only for hash-locked escrows
+        preimage: Option<[u8; 32]>,
+    },
+    /// Splits `amount` tokens off an open escrow into a second escrow with its own withdrawer.
+    /// Not available for escrows initialized with `freeze` or sealed ones, and like
+    /// `CancelEscrow` only once an expiry passed.
+    ///
+    /// The new escrow follows the same milestones, and what was already withdrawn is divided
+    /// between the two in proportion to their deposits so neither releases early. The reserve
+    /// stays with the original escrow, which must keep at least that much.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the person who initialized the original escrow
+    /// 1. `[writable]` The original escrow account
+    /// 2. `[writable]` The PDA's temp token account of the original escrow
+    /// 3. `[writable]` New temp token account that should be created prior to this instruction
+    ///    and owned by the initializer
+    /// 4. `[]` The new escrow's withdrawer pubkey
+    /// 5. `[writable]` The new escrow account
+    /// 6. `[]` The token program
+    /// 7. `[]` The PDA account
+    /// 8. `[writable]` The initializer's escrow counter PDA, only with the `escrow-limit` feature
+    /// 9. `[]` The system program, only with the `escrow-limit` feature
+    ///
+    /// The clock sysvar may be passed as the very last account, otherwise it is read through
+    /// `Clock::get`.
+    SplitEscrow {
+        /// The amount to move into the new escrow, must be less than the deposited amount
+        amount: u64,
+    },
+    /// Merges a second escrow into the first. Both must belong to the same initializer and share
+    /// the withdrawer, mint, reserve, milestones and other settings. The second escrow's deposit
+    /// moves over and the escrow is closed, what was withdrawn from it counts towards the first.
+    /// Its temp token account is closed too, or handed back to the initializer when it holds
+    /// more than the deposit. Not available for escrows initialized with `freeze` or sealed
+    /// ones.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The initializer of both escrows, receives the reclaimed rent
+    /// 1. `[writable]` The escrow account to merge into
+    /// 2. `[writable]` The PDA's temp token account of the escrow to merge into
+    /// 3. `[writable]` The escrow account to merge and close
+    /// 4. `[writable]` The PDA's temp token account of the escrow to merge and close
+    /// 5. `[]` The token program
+    /// 6. `[]` The PDA account
+    /// 7. `[writable]` The initializer's escrow counter PDA, only with the `escrow-limit` feature
+    /// 8. `[writable]` The treasury PDA, only when the merged escrow was initialized with
+    ///    `rent_to_treasury`
+    /// 9. `[writable]` The merged escrow's registry entry, only when it was initialized with
+    ///    `use_registry`. The entry moves over to the escrow merged into
+    MergeEscrow,
+    /// Returns the deposited amount to the initializer and sets it to zero, leaving the escrow
+    /// account open. Not available for escrows initialized with `freeze` or sealed ones, and
+    /// like `CancelEscrow` only once an expiry passed.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the person who initialized the escrow
+    /// 1. `[writable]` The escrow account
+    /// 2. `[writable]` The PDA's temp token account
+    /// 3. `[writable]` The initializer's token account to receive the tokens
+    /// 4. `[]` The token program
+    /// 5. `[]` The PDA account
+    ///
+    /// The clock sysvar may be passed as the very last account, otherwise it is read through
+    /// `Clock::get`.
+    Drain,
+    /// Lets the withdrawer give up the escrow, returning the tokens and rent to the initializer
+    /// and closing the escrow
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The withdrawer
+    /// 1. `[writable]` The escrow account
+    /// 2. `[writable]` The PDA's temp token account, closed unless the escrow uses `freeze` or
+    ///    handed to the initializer when it holds more than the deposit
+    /// 3. `[writable]` The initializer's token account to receive the tokens, must be owned by
+    ///    the initializer
+    /// 4. `[writable]` The initializer's main account to send their rent fees to
+    /// 5. `[]` The token program
+    /// 6. `[]` The PDA account
+    /// 7. `[]` The temp token account's mint, only for escrows initialized with `freeze`. For
+    ///    escrows with a `dust_threshold`, the `[writable]` escrow's dust collector token account
+    ///    takes its place
+    /// 8. `[writable]` The initializer's escrow counter PDA, only with the `escrow-limit` feature.
+    ///    Follows the PDA account directly when there is no mint or dust collector
+    /// 9. `[writable]` The treasury PDA, only for escrows initialized with `rent_to_treasury`
+    /// 10. `[writable]` The registry entry, only for escrows initialized with `use_registry`
+    ///
+    /// A `freeze` escrow's temp account is thawed. The PDA's delegate approval on it can only be
+    /// revoked by its owner, so that happens only when account 4 signs as well.
+    Decline,
+    /// Lets the initializer call the escrow off, taking back whatever is left after any partial
+    /// withdraws together with the rent, and closing the escrow
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The initializer, receives the rent fees
+    /// 1. `[writable]` The escrow account
+    /// 2. `[writable]` The PDA's temp token account, closed unless the escrow uses `freeze` or
+    ///    handed to the initializer when it holds more than the deposit
+    /// 3. `[writable]` The initializer's token account to receive the tokens
+    /// 4. `[]` The token program
+    /// 5. `[]` The PDA account
+    /// 6. `[]` The temp token account's mint, only for escrows initialized with `freeze`. For
+    ///    escrows with a `dust_threshold`, the `[writable]` escrow's dust collector token account
+    ///    takes its place
+    /// 7. `[writable]` The initializer's escrow counter PDA, only with the `escrow-limit` feature.
+    ///    Follows the PDA account directly when there is no mint or dust collector
+    /// 8. `[writable]` The treasury PDA, only for escrows initialized with `rent_to_treasury`
+    /// 9. `[writable]` The registry entry, only for escrows initialized with `use_registry`
+    ///
+    /// For escrows initialized with `lamports`, only the initializer, the escrow account and the
+    /// escrow counter are expected.
+    ///
+    /// A `freeze` escrow's temp account is thawed and the PDA's delegate approval on it revoked.
+    ///
+    /// Escrows with an expiry can only be cancelled once it passed, sealed ones not at all
+    /// unless they expired. The clock sysvar may be passed as the very last account, otherwise
+    /// it is read through `Clock::get`.
+    CancelEscrow,
+    /// Fails unless the escrow is open for `expected_withdrawer` with at least `min_remaining`
+    /// still deposited. Changes nothing, it only lets composed transactions fail early.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[]` The escrow account
+    AssertState {
+        /// The withdrawer the escrow must have been initialized for
+        expected_withdrawer: Pubkey,
+        /// The smallest deposited amount the escrow may have left
+        min_remaining: u64,
+    },
+    /// Partially withdraws the sum of `allocations`, spreading it over several of the taker's
+    /// token accounts. Must leave part of the deposit in the escrow, a withdraw emptying it goes
+    /// through `Withdraw`. Not available for escrows initialized with `freeze`, `lamports` or a
+    /// `hashlock`.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the person taking the trade
+    /// 1. `[writable]` The escrow account
+    /// 2. `[writable]` The PDA's temp token account
+    /// 3. `[]` The token program
+    /// 4. `[]` The PDA account
+    /// 5. `[writable]` The first of the taker's token accounts to receive the tokens, the others
+    ///    follow it. The allocations index into this list
+    ///
+    /// The instructions and clock sysvars may follow as for `Withdraw`.
+    ///
+    /// Return data: as for `Withdraw`, the first byte always `0` as the escrow stays open
+    /// and the status never `Closed`
+    WithdrawSplit {
+        /// Pairs of an index into the receiving token accounts and the amount it gets
+        allocations: Vec<(u8, u64)>,
+    },
+    /// Reports the escrow's [state_hash](../state/struct.Escrow.html#method.state_hash) without
+    /// changing anything
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[]` The escrow account
+    ///
+    /// Return data: the 32 bytes of the hash
+    StateHash,
+    /// Permanently seals the escrow, after which `SplitEscrow`, `MergeEscrow`, `Drain`,
+    /// `CancelPartial` and `CancelEscrow` fail, the latter only until an expiry passed. There is
+    /// no way to unseal it.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the person who initialized the escrow
+    /// 1. `[writable]` The escrow account
+    Seal,
+    /// Returns `amount` tokens to the initializer and leaves the escrow open with the rest. The
+    /// remainder may not drop below the reserve, use `CancelEscrow` to return everything.
+    /// Not available for escrows initialized with `freeze` or `lamports` or sealed ones, and
+    /// like `CancelEscrow` only once an expiry passed.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the person who initialized the escrow
+    /// 1. `[writable]` The escrow account
+    /// 2. `[writable]` The PDA's temp token account
+    /// 3. `[writable]` The initializer's token account to receive the tokens
+    /// 4. `[]` The token program
+    /// 5. `[]` The PDA account
+    ///
+    /// The clock sysvar may be passed as the very last account, otherwise it is read through
+    /// `Clock::get`.
+    CancelPartial {
+        /// The amount to return, must be less than the deposited amount
+        amount: u64,
+    },
+    /// Moves lamports out of the treasury PDA that escrows initialized with `rent_to_treasury`
+    /// pay their rent to. Only the program's upgrade authority can withdraw. The treasury has
+    /// to be either emptied or left rent exempt.
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The program's upgrade authority
+    /// 1. `[writable]` The treasury PDA
+    /// 2. `[writable]` The account to receive the lamports
+    /// 3. `[]` The program's data account under the upgradeable BPF loader
+    /// 4. `[]` The system program
+    WithdrawTreasury {
+        /// The amount of lamports to withdraw
+        amount: u64,
+    },
 }
 
 impl EscrowInstruction {
     /// Unpacks a byte buffer into a [EscrowInstruction](enum.EscrowInstruction.html).
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
-        let (tag, rest) = input.split_first().ok_or(InvalidInstruction)?;
+        let (&tag, rest) = input.split_first().ok_or(InvalidInstruction)?;
 
-        Ok(match tag {
-            0 => Self::InitEscrow {
+        Ok(match InstructionTag::try_from(tag)? {
+            InstructionTag::InitEscrow => Self::InitEscrow(InitEscrowParams::from_bytes(rest)?),
+            InstructionTag::Withdraw => Self::Withdraw {
+                amount: Self::unpack_amount(rest)?,
+                preimage: match rest.len() {
+                    8 => None,
+                    40 => Some(*array_ref![rest, 8, 32]),
+                    _ => return Err(InvalidInstruction.into()),
+                },
+            },
+            InstructionTag::SplitEscrow => Self::SplitEscrow {
                 amount: Self::unpack_amount(rest)?,
             },
-            1 => Self::Withdraw {
+            InstructionTag::MergeEscrow => Self::MergeEscrow,
+            InstructionTag::Drain => Self::Drain,
+            InstructionTag::Decline => Self::Decline,
+            InstructionTag::CancelEscrow => Self::CancelEscrow,
+            InstructionTag::AssertState => {
+                let expected_withdrawer = rest
+                    .get(..32)
+                    .and_then(|slice| slice.try_into().ok())
+                    .map(Pubkey::new_from_array)
+                    .ok_or(InvalidInstruction)?;
+                Self::AssertState {
+                    expected_withdrawer,
+                    min_remaining: Self::unpack_amount(rest.get(32..).ok_or(InvalidInstruction)?)?,
+                }
+            }
+            InstructionTag::WithdrawSplit => {
+                let (&count, rest) = rest.split_first().ok_or(InvalidInstruction)?;
+                if rest.len() != count as usize * 9 {
+                    return Err(InvalidInstruction.into());
+                }
+                let allocations = rest
+                    .chunks(9)
+                    .map(|allocation| Ok((allocation[0], Self::unpack_amount(&allocation[1..])?)))
+                    .collect::<Result<_, ProgramError>>()?;
+                Self::WithdrawSplit { allocations }
+            }
+            InstructionTag::StateHash => Self::StateHash,
+            InstructionTag::Seal => Self::Seal,
+            InstructionTag::CancelPartial => Self::CancelPartial {
+                amount: Self::unpack_amount(rest)?,
+            },
+            InstructionTag::WithdrawTreasury => Self::WithdrawTreasury {
                 amount: Self::unpack_amount(rest)?,
             },
-            _ => return Err(InvalidInstruction.into()),
         })
     }
 
+    /// Packs a [EscrowInstruction](enum.EscrowInstruction.html) into a byte buffer.
+    pub fn pack(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(1 + InitEscrowParams::LEN);
+        match self {
+            Self::InitEscrow(params) => {
+                buf.push(InstructionTag::InitEscrow as u8);
+                buf.extend_from_slice(&params.to_bytes());
+            }
+            Self::Withdraw { amount, preimage } => {
+                buf.push(InstructionTag::Withdraw as u8);
+                buf.extend_from_slice(&amount.to_le_bytes());
+                if let Some(preimage) = preimage {
+                    buf.extend_from_slice(preimage);
+                }
+            }
+            Self::SplitEscrow { amount } => {
+                buf.push(InstructionTag::SplitEscrow as u8);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::MergeEscrow => buf.push(InstructionTag::MergeEscrow as u8),
+            Self::Drain => buf.push(InstructionTag::Drain as u8),
+            Self::Decline => buf.push(InstructionTag::Decline as u8),
+            Self::CancelEscrow => buf.push(InstructionTag::CancelEscrow as u8),
+            Self::AssertState {
+                expected_withdrawer,
+                min_remaining,
+            } => {
+                buf.push(InstructionTag::AssertState as u8);
+                buf.extend_from_slice(expected_withdrawer.as_ref());
+                buf.extend_from_slice(&min_remaining.to_le_bytes());
+            }
+            Self::WithdrawSplit { allocations } => {
+                buf.push(InstructionTag::WithdrawSplit as u8);
+                buf.push(allocations.len() as u8);
+                for (index, amount) in allocations {
+                    buf.push(*index);
+                    buf.extend_from_slice(&amount.to_le_bytes());
+                }
+            }
+            Self::StateHash => buf.push(InstructionTag::StateHash as u8),
+            Self::Seal => buf.push(InstructionTag::Seal as u8),
+            Self::CancelPartial { amount } => {
+                buf.push(InstructionTag::CancelPartial as u8);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::WithdrawTreasury { amount } => {
+                buf.push(InstructionTag::WithdrawTreasury as u8);
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+        }
+        buf
+    }
+
     fn unpack_amount(input: &[u8]) -> Result<u64, ProgramError> {
         let amount = input
             .get(..8)
@@ -61,5 +702,827 @@ impl EscrowInstruction {
             .ok_or(InvalidInstruction)?;
         Ok(amount)
     }
+
+    fn unpack_flag(input: &[u8]) -> Result<bool, ProgramError> {
+        match input.first() {
+            Some(0) => Ok(false),
+            Some(1) => Ok(true),
+            _ => Err(InvalidInstruction.into()),
+        }
+    }
+}
+
+/// Builds every escrow instruction with its accounts in the order the processor expects
+pub struct EscrowInstructionBuilder {
+    program_id: Pubkey,
+}
+
+impl EscrowInstructionBuilder {
+    pub fn new(program_id: Pubkey) -> Self {
+        Self { program_id }
+    }
+
+    fn pda(&self) -> Pubkey {
+        Pubkey::find_program_address(&[b"escrow"], &self.program_id).0
+    }
+
+    /// The treasury PDA that escrows initialized with `rent_to_treasury` pay their rent to. It
+    /// has to be appended to the accounts of any instruction closing such an escrow.
+    pub fn treasury(&self) -> Pubkey {
+        Pubkey::find_program_address(&[b"treasury"], &self.program_id).0
+    }
+
+    /// The vault holding the tokens of `escrow_account` when it is initialized with `vault`
+    pub fn vault(&self, escrow_account: &Pubkey) -> Pubkey {
+        Pubkey::find_program_address(&[b"vault", escrow_account.as_ref()], &self.program_id).0
+    }
+
+    /// The registry entry of escrows initialized with `use_registry`. It has to be appended, after
+    /// the treasury, to the accounts of any instruction closing such an escrow.
+    pub fn registry(&self, initializer: &Pubkey, mint: &Pubkey, withdrawer: &Pubkey) -> Pubkey {
+        Pubkey::find_program_address(
+            &[
+                b"registry",
+                initializer.as_ref(),
+                mint.as_ref(),
+                withdrawer.as_ref(),
+            ],
+            &self.program_id,
+        )
+        .0
+    }
+
+    #[cfg(feature = "escrow-limit")]
+    fn counter(&self, initializer: &Pubkey) -> Pubkey {
+        Pubkey::find_program_address(&[b"counter", initializer.as_ref()], &self.program_id).0
+    }
+
+    fn instruction(&self, accounts: Vec<AccountMeta>, data: EscrowInstruction) -> Instruction {
+        Instruction {
+            program_id: self.program_id,
+            accounts,
+            data: data.pack(),
+        }
+    }
+
+    /// Creates an `InitEscrow` instruction. `mint` is the temp token account's mint.
+    #[allow(clippy::too_many_arguments)]
+    pub fn init_escrow(
+        &self,
+        initializer: &Pubkey,
+        temp_token_account: &Pubkey,
+        withdrawer: &Pubkey,
+        escrow_account: &Pubkey,
+        mint: &Pubkey,
+        params: InitEscrowParams,
+    ) -> Result<Instruction, ProgramError> {
+        let mut accounts = vec![
+            if params.use_registry || params.vault || cfg!(feature = "escrow-limit") {
+                AccountMeta::new(*initializer, true)
+            } else {
+                AccountMeta::new_readonly(*initializer, true)
+            },
+            AccountMeta::new(*temp_token_account, false),
+            AccountMeta::new_readonly(*withdrawer, false),
+            AccountMeta::new(*escrow_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ];
+        if params.freeze {
+            accounts.push(AccountMeta::new_readonly(*mint, false));
+            accounts.push(AccountMeta::new_readonly(self.pda(), false));
+        }
+        if params.vault {
+            accounts.push(AccountMeta::new(self.vault(escrow_account), false));
+            accounts.push(AccountMeta::new_readonly(*mint, false));
+            accounts.push(AccountMeta::new_readonly(system_program::id(), false));
+        }
+        if params.use_registry {
+            accounts.push(AccountMeta::new(
+                self.registry(initializer, mint, withdrawer),
+                false,
+            ));
+            accounts.push(AccountMeta::new_readonly(system_program::id(), false));
+        }
+        #[cfg(feature = "escrow-limit")]
+        {
+            accounts.push(AccountMeta::new(self.counter(initializer), false));
+            accounts.push(AccountMeta::new_readonly(system_program::id(), false));
+        }
+
+        Ok(self.instruction(accounts, EscrowInstruction::InitEscrow(params)))
+    }
+
+    /// Creates a `Withdraw` instruction. `mint` must be given for escrows initialized with
+    /// `freeze`, `preimage` for escrows with a `hashlock`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn withdraw(
+        &self,
+        taker: &Pubkey,
+        takers_token_to_receive_account: &Pubkey,
+        pdas_temp_token_account: &Pubkey,
+        initializers_main_account: &Pubkey,
+        escrow_account: &Pubkey,
+        mint: Option<&Pubkey>,
+        amount: u64,
+        preimage: Option<[u8; 32]>,
+    ) -> Result<Instruction, ProgramError> {
+        if amount == 0 {
+            return Err(EscrowError::ZeroAmount.into());
+        }
+
+        let mut accounts = vec![
+            AccountMeta::new_readonly(*taker, true),
+            AccountMeta::new(*takers_token_to_receive_account, false),
+            AccountMeta::new(*pdas_temp_token_account, false),
+            AccountMeta::new(*initializers_main_account, false),
+            AccountMeta::new(*escrow_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(self.pda(), false),
+        ];
+        if let Some(mint) = mint {
+            accounts.push(AccountMeta::new_readonly(*mint, false));
+        }
+        #[cfg(feature = "escrow-limit")]
+        accounts.push(AccountMeta::new(self.counter(initializers_main_account), false));
+
+        Ok(self.instruction(accounts, EscrowInstruction::Withdraw { amount, preimage }))
+    }
+
+    /// Creates an `InitEscrow` instruction for an escrow of lamports, `params.lamports` must be set
+    pub fn init_lamport_escrow(
+        &self,
+        initializer: &Pubkey,
+        withdrawer: &Pubkey,
+        escrow_account: &Pubkey,
+        params: InitEscrowParams,
+    ) -> Result<Instruction, ProgramError> {
+        if !params.lamports {
+            return Err(InvalidInstruction.into());
+        }
+
+        #[allow(unused_mut)]
+        let mut accounts = vec![
+            AccountMeta::new(*initializer, true),
+            AccountMeta::new_readonly(*withdrawer, false),
+            AccountMeta::new(*escrow_account, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ];
+        #[cfg(feature = "escrow-limit")]
+        accounts.push(AccountMeta::new(self.counter(initializer), false));
+
+        Ok(self.instruction(accounts, EscrowInstruction::InitEscrow(params)))
+    }
+
+    /// Creates a `Withdraw` instruction for an escrow initialized with `create_receive_account`,
+    /// paying into the taker's associated token account for `mint`. `freeze` must match the
+    /// escrow's.
+    #[allow(clippy::too_many_arguments)]
+    pub fn withdraw_to_associated_account(
+        &self,
+        taker: &Pubkey,
+        pdas_temp_token_account: &Pubkey,
+        initializers_main_account: &Pubkey,
+        escrow_account: &Pubkey,
+        mint: &Pubkey,
+        freeze: bool,
+        amount: u64,
+        preimage: Option<[u8; 32]>,
+    ) -> Result<Instruction, ProgramError> {
+        let mut instruction = self.withdraw(
+            taker,
+            &get_associated_token_address(taker, mint),
+            pdas_temp_token_account,
+            initializers_main_account,
+            escrow_account,
+            if freeze { Some(mint) } else { None },
+            amount,
+            preimage,
+        )?;
+        instruction.accounts[0].is_writable = true;
+        let index = if freeze { 8 } else { 7 };
+        instruction.accounts.splice(
+            index..index,
+            [
+                AccountMeta::new_readonly(*mint, false),
+                AccountMeta::new_readonly(system_program::id(), false),
+                AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+            ],
+        );
+
+        Ok(instruction)
+    }
+
+    /// Creates a `Withdraw` instruction for an escrow initialized with `lamports`
+    pub fn withdraw_lamports(
+        &self,
+        taker: &Pubkey,
+        escrow_account: &Pubkey,
+        initializers_main_account: &Pubkey,
+        amount: u64,
+        preimage: Option<[u8; 32]>,
+    ) -> Result<Instruction, ProgramError> {
+        if amount == 0 {
+            return Err(EscrowError::ZeroAmount.into());
+        }
+
+        #[allow(unused_mut)]
+        let mut accounts = vec![
+            AccountMeta::new(*taker, true),
+            AccountMeta::new(*escrow_account, false),
+            AccountMeta::new(*initializers_main_account, false),
+        ];
+        #[cfg(feature = "escrow-limit")]
+        accounts.push(AccountMeta::new(self.counter(initializers_main_account), false));
+
+        Ok(self.instruction(accounts, EscrowInstruction::Withdraw { amount, preimage }))
+    }
+
+    /// Creates a `SplitEscrow` instruction
+    #[allow(clippy::too_many_arguments)]
+    pub fn split_escrow(
+        &self,
+        initializer: &Pubkey,
+        escrow_account: &Pubkey,
+        pdas_temp_token_account: &Pubkey,
+        new_temp_token_account: &Pubkey,
+        new_withdrawer: &Pubkey,
+        new_escrow_account: &Pubkey,
+        amount: u64,
+    ) -> Result<Instruction, ProgramError> {
+        #[allow(unused_mut)]
+        let mut accounts = vec![
+            if cfg!(feature = "escrow-limit") {
+                AccountMeta::new(*initializer, true)
+            } else {
+                AccountMeta::new_readonly(*initializer, true)
+            },
+            AccountMeta::new(*escrow_account, false),
+            AccountMeta::new(*pdas_temp_token_account, false),
+            AccountMeta::new(*new_temp_token_account, false),
+            AccountMeta::new_readonly(*new_withdrawer, false),
+            AccountMeta::new(*new_escrow_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(self.pda(), false),
+        ];
+        #[cfg(feature = "escrow-limit")]
+        {
+            accounts.push(AccountMeta::new(self.counter(initializer), false));
+            accounts.push(AccountMeta::new_readonly(system_program::id(), false));
+        }
+
+        Ok(self.instruction(accounts, EscrowInstruction::SplitEscrow { amount }))
+    }
+
+    /// Creates a `MergeEscrow` instruction
+    pub fn merge_escrow(
+        &self,
+        initializer: &Pubkey,
+        escrow_account: &Pubkey,
+        pdas_temp_token_account: &Pubkey,
+        merged_escrow_account: &Pubkey,
+        merged_pdas_temp_token_account: &Pubkey,
+    ) -> Result<Instruction, ProgramError> {
+        #[allow(unused_mut)]
+        let mut accounts = vec![
+            AccountMeta::new(*initializer, true),
+            AccountMeta::new(*escrow_account, false),
+            AccountMeta::new(*pdas_temp_token_account, false),
+            AccountMeta::new(*merged_escrow_account, false),
+            AccountMeta::new(*merged_pdas_temp_token_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(self.pda(), false),
+        ];
+        #[cfg(feature = "escrow-limit")]
+        accounts.push(AccountMeta::new(self.counter(initializer), false));
+
+        Ok(self.instruction(accounts, EscrowInstruction::MergeEscrow))
+    }
+
+    /// Creates a `Drain` instruction
+    pub fn drain(
+        &self,
+        initializer: &Pubkey,
+        escrow_account: &Pubkey,
+        pdas_temp_token_account: &Pubkey,
+        initializers_token_account: &Pubkey,
+    ) -> Result<Instruction, ProgramError> {
+        let accounts = vec![
+            AccountMeta::new_readonly(*initializer, true),
+            AccountMeta::new(*escrow_account, false),
+            AccountMeta::new(*pdas_temp_token_account, false),
+            AccountMeta::new(*initializers_token_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(self.pda(), false),
+        ];
+
+        Ok(self.instruction(accounts, EscrowInstruction::Drain))
+    }
+
+    /// Creates a `Decline` instruction. `mint` must be given for escrows initialized with
+    /// `freeze`, `dust_collector` for escrows with a `dust_threshold`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn decline(
+        &self,
+        withdrawer: &Pubkey,
+        escrow_account: &Pubkey,
+        pdas_temp_token_account: &Pubkey,
+        initializers_token_account: &Pubkey,
+        initializers_main_account: &Pubkey,
+        mint: Option<&Pubkey>,
+        dust_collector: Option<&Pubkey>,
+    ) -> Result<Instruction, ProgramError> {
+        let mut accounts = vec![
+            AccountMeta::new_readonly(*withdrawer, true),
+            AccountMeta::new(*escrow_account, false),
+            AccountMeta::new(*pdas_temp_token_account, false),
+            AccountMeta::new(*initializers_token_account, false),
+            AccountMeta::new(*initializers_main_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(self.pda(), false),
+        ];
+        if let Some(mint) = mint {
+            accounts.push(AccountMeta::new_readonly(*mint, false));
+        }
+        if let Some(dust_collector) = dust_collector {
+            accounts.push(AccountMeta::new(*dust_collector, false));
+        }
+        #[cfg(feature = "escrow-limit")]
+        accounts.push(AccountMeta::new(self.counter(initializers_main_account), false));
+
+        Ok(self.instruction(accounts, EscrowInstruction::Decline))
+    }
+
+    /// Creates a `CancelEscrow` instruction. `mint` must be given for escrows initialized with
+    /// `freeze`, `dust_collector` for escrows with a `dust_threshold`.
+    pub fn cancel_escrow(
+        &self,
+        initializer: &Pubkey,
+        escrow_account: &Pubkey,
+        pdas_temp_token_account: &Pubkey,
+        initializers_token_account: &Pubkey,
+        mint: Option<&Pubkey>,
+        dust_collector: Option<&Pubkey>,
+    ) -> Result<Instruction, ProgramError> {
+        let mut accounts = vec![
+            AccountMeta::new(*initializer, true),
+            AccountMeta::new(*escrow_account, false),
+            AccountMeta::new(*pdas_temp_token_account, false),
+            AccountMeta::new(*initializers_token_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(self.pda(), false),
+        ];
+        if let Some(mint) = mint {
+            accounts.push(AccountMeta::new_readonly(*mint, false));
+        }
+        if let Some(dust_collector) = dust_collector {
+            accounts.push(AccountMeta::new(*dust_collector, false));
+        }
+        #[cfg(feature = "escrow-limit")]
+        accounts.push(AccountMeta::new(self.counter(initializer), false));
+
+        Ok(self.instruction(accounts, EscrowInstruction::CancelEscrow))
+    }
+
+    /// Creates a `CancelEscrow` instruction for an escrow initialized with `lamports`
+    pub fn cancel_lamport_escrow(
+        &self,
+        initializer: &Pubkey,
+        escrow_account: &Pubkey,
+    ) -> Result<Instruction, ProgramError> {
+        #[allow(unused_mut)]
+        let mut accounts = vec![
+            AccountMeta::new(*initializer, true),
+            AccountMeta::new(*escrow_account, false),
+        ];
+        #[cfg(feature = "escrow-limit")]
+        accounts.push(AccountMeta::new(self.counter(initializer), false));
+
+        Ok(self.instruction(accounts, EscrowInstruction::CancelEscrow))
+    }
+
+    /// Creates an `AssertState` instruction
+    pub fn assert_state(
+        &self,
+        escrow_account: &Pubkey,
+        expected_withdrawer: &Pubkey,
+        min_remaining: u64,
+    ) -> Instruction {
+        self.instruction(
+            vec![AccountMeta::new_readonly(*escrow_account, false)],
+            EscrowInstruction::AssertState {
+                expected_withdrawer: *expected_withdrawer,
+                min_remaining,
+            },
+        )
+    }
+
+    /// Creates a `WithdrawSplit` instruction paying `amount` to each of `allocations`
+    pub fn withdraw_split(
+        &self,
+        taker: &Pubkey,
+        escrow_account: &Pubkey,
+        pdas_temp_token_account: &Pubkey,
+        allocations: &[(Pubkey, u64)],
+    ) -> Result<Instruction, ProgramError> {
+        if allocations.is_empty() || allocations.len() > u8::MAX as usize {
+            return Err(InvalidInstruction.into());
+        }
+        if allocations.iter().any(|&(_, amount)| amount == 0) {
+            return Err(EscrowError::ZeroAmount.into());
+        }
+
+        let mut accounts = vec![
+            AccountMeta::new_readonly(*taker, true),
+            AccountMeta::new(*escrow_account, false),
+            AccountMeta::new(*pdas_temp_token_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(self.pda(), false),
+        ];
+        accounts.extend(
+            allocations
+                .iter()
+                .map(|(receive_account, _)| AccountMeta::new(*receive_account, false)),
+        );
+
+        Ok(self.instruction(
+            accounts,
+            EscrowInstruction::WithdrawSplit {
+                allocations: (0..)
+                    .zip(allocations.iter().map(|&(_, amount)| amount))
+                    .collect(),
+            },
+        ))
+    }
+
+    /// Creates a `StateHash` instruction
+    pub fn state_hash(&self, escrow_account: &Pubkey) -> Instruction {
+        self.instruction(
+            vec![AccountMeta::new_readonly(*escrow_account, false)],
+            EscrowInstruction::StateHash,
+        )
+    }
+
+    /// Creates a `Seal` instruction
+    pub fn seal(&self, initializer: &Pubkey, escrow_account: &Pubkey) -> Instruction {
+        self.instruction(
+            vec![
+                AccountMeta::new_readonly(*initializer, true),
+                AccountMeta::new(*escrow_account, false),
+            ],
+            EscrowInstruction::Seal,
+        )
+    }
+
+    /// Creates a `CancelPartial` instruction
+    pub fn cancel_partial(
+        &self,
+        initializer: &Pubkey,
+        escrow_account: &Pubkey,
+        pdas_temp_token_account: &Pubkey,
+        initializers_token_account: &Pubkey,
+        amount: u64,
+    ) -> Result<Instruction, ProgramError> {
+        if amount == 0 {
+            return Err(EscrowError::ZeroAmount.into());
+        }
+
+        let accounts = vec![
+            AccountMeta::new_readonly(*initializer, true),
+            AccountMeta::new(*escrow_account, false),
+            AccountMeta::new(*pdas_temp_token_account, false),
+            AccountMeta::new(*initializers_token_account, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(self.pda(), false),
+        ];
+
+        Ok(self.instruction(accounts, EscrowInstruction::CancelPartial { amount }))
+    }
+
+    /// Creates a `WithdrawTreasury` instruction
+    pub fn withdraw_treasury(
+        &self,
+        upgrade_authority: &Pubkey,
+        recipient: &Pubkey,
+        amount: u64,
+    ) -> Result<Instruction, ProgramError> {
+        if amount == 0 {
+            return Err(EscrowError::ZeroAmount.into());
+        }
+
+        let program_data = Pubkey::find_program_address(
+            &[self.program_id.as_ref()],
+            &bpf_loader_upgradeable::id(),
+        )
+        .0;
+        let accounts = vec![
+            AccountMeta::new_readonly(*upgrade_authority, true),
+            AccountMeta::new(self.treasury(), false),
+            AccountMeta::new(*recipient, false),
+            AccountMeta::new_readonly(program_data, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ];
+
+        Ok(self.instruction(accounts, EscrowInstruction::WithdrawTreasury { amount }))
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn init_escrow_wire_format() {
+        let mut data = vec![0u8; 1 + InitEscrowParams::LEN];
+        data[0] = 0;
+        data[1..9].copy_from_slice(&[0xe8, 0x03, 0, 0, 0, 0, 0, 0]);
+
+        let instruction = EscrowInstruction::unpack(&data).unwrap();
+        match &instruction {
+            EscrowInstruction::InitEscrow(params) => assert_eq!(params.amount, 1000),
+            _ => panic!("expected InitEscrow"),
+        }
+        assert_eq!(instruction.pack(), data);
+    }
+
+    #[test]
+    fn withdraw_wire_format() {
+        let data = [1, 0xe8, 0x03, 0, 0, 0, 0, 0, 0];
+
+        let instruction = EscrowInstruction::unpack(&data).unwrap();
+        match &instruction {
+            EscrowInstruction::Withdraw { amount, preimage } => {
+                assert_eq!(*amount, 1000);
+                assert_eq!(*preimage, None);
+            }
+            _ => panic!("expected Withdraw"),
+        }
+        assert_eq!(instruction.pack(), data);
+        assert_eq!(
+            EscrowInstruction::Withdraw {
+                amount: 1000,
+                preimage: None,
+            }
+            .pack(),
+            data
+        );
+    }
+
+    #[test]
+    fn unpack_amount_is_little_endian() {
+        let amount = 0x0102_0304_0506_0708u64;
+        let little_endian = [0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01];
+
+        assert_eq!(EscrowInstruction::unpack_amount(&little_endian).unwrap(), amount);
+        assert_ne!(
+            EscrowInstruction::unpack_amount(&amount.to_be_bytes()).unwrap(),
+            amount
+        );
+    }
+
+    #[test]
+    fn instruction_tag_from_u8() {
+        let tags = [
+            InstructionTag::InitEscrow,
+            InstructionTag::Withdraw,
+            InstructionTag::SplitEscrow,
+            InstructionTag::MergeEscrow,
+            InstructionTag::Drain,
+            InstructionTag::Decline,
+            InstructionTag::CancelEscrow,
+            InstructionTag::AssertState,
+            InstructionTag::WithdrawSplit,
+            InstructionTag::StateHash,
+            InstructionTag::Seal,
+            InstructionTag::CancelPartial,
+            InstructionTag::WithdrawTreasury,
+        ];
+        for (byte, tag) in (0u8..).zip(tags.iter()) {
+            assert_eq!(InstructionTag::try_from(byte).unwrap(), *tag);
+            assert_eq!(*tag as u8, byte);
+        }
+
+        assert!(matches!(
+            InstructionTag::try_from(tags.len() as u8),
+            Err(EscrowError::InvalidInstruction)
+        ));
+        assert_eq!(
+            EscrowInstruction::unpack(&[u8::MAX]).err(),
+            Some(InvalidInstruction.into())
+        );
+        assert_eq!(EscrowInstruction::unpack(&[]).err(), Some(InvalidInstruction.into()));
+    }
+
+    fn params_bytes(amount: u64) -> Vec<u8> {
+        let mut data = vec![0u8; InitEscrowParams::LEN];
+        data[..8].copy_from_slice(&amount.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn init_escrow_params_round_trip() {
+        let mut data = params_bytes(1000);
+        data[9..17].copy_from_slice(&100u64.to_le_bytes());
+        data[150..158].copy_from_slice(&42u64.to_le_bytes());
+
+        let params = InitEscrowParams::from_bytes(&data).unwrap();
+        assert_eq!(params.amount, 1000);
+        assert_eq!(params.reserve_amount, 100);
+        assert!(!params.full_only);
+        assert_eq!(params.reference_id, 42);
+        assert_eq!(params.to_bytes(), data);
+    }
+
+    #[test]
+    fn init_escrow_accepts_a_lone_amount() {
+        let params = InitEscrowParams::from_bytes(&1000u64.to_le_bytes()).unwrap();
+        assert_eq!(params, InitEscrowParams::new(1000));
+        assert_eq!(params.to_bytes(), params_bytes(1000));
+
+        let data = [&[InstructionTag::InitEscrow as u8][..], &1000u64.to_le_bytes()].concat();
+        assert!(matches!(
+            EscrowInstruction::unpack(&data),
+            Ok(EscrowInstruction::InitEscrow(params)) if params == InitEscrowParams::new(1000)
+        ));
+
+        assert_eq!(
+            InitEscrowParams::from_bytes(&[0; 9]).err(),
+            Some(InvalidInstruction.into())
+        );
+    }
+
+    #[test]
+    fn init_escrow_params_reject_malformed_buffers() {
+        let data = params_bytes(1000);
+        assert_eq!(
+            InitEscrowParams::from_bytes(&data[1..]).err(),
+            Some(InvalidInstruction.into())
+        );
+        assert_eq!(
+            InitEscrowParams::from_bytes(&[data.as_slice(), &[0]].concat()).err(),
+            Some(InvalidInstruction.into())
+        );
+
+        // a flag that is neither 0 nor 1
+        let mut data = params_bytes(1000);
+        data[8] = 2;
+        assert_eq!(
+            InitEscrowParams::from_bytes(&data).err(),
+            Some(InvalidInstruction.into())
+        );
+
+        // a reserve larger than the amount
+        let mut data = params_bytes(1000);
+        data[9..17].copy_from_slice(&1001u64.to_le_bytes());
+        assert_eq!(
+            InitEscrowParams::from_bytes(&data).err(),
+            Some(InvalidInstruction.into())
+        );
+
+        // a reserve on an escrow without partial withdraws
+        let mut data = params_bytes(1000);
+        data[9..17].copy_from_slice(&100u64.to_le_bytes());
+        data[62] = 1;
+        assert_eq!(
+            InitEscrowParams::from_bytes(&data).err(),
+            Some(InvalidInstruction.into())
+        );
+
+        // `freeze` together with `vault`
+        let mut data = params_bytes(1000);
+        data[8] = 1;
+        data[19] = 1;
+        assert_eq!(
+            InitEscrowParams::from_bytes(&data).err(),
+            Some(InvalidInstruction.into())
+        );
+
+        // a dust threshold without a dust collector
+        let mut data = params_bytes(1000);
+        data[95..103].copy_from_slice(&5u64.to_le_bytes());
+        assert_eq!(
+            InitEscrowParams::from_bytes(&data).err(),
+            Some(InvalidInstruction.into())
+        );
+
+        let mut data = params_bytes(1000);
+        data[20] = MAX_MILESTONES as u8 + 1;
+        assert_eq!(
+            InitEscrowParams::from_bytes(&data).err(),
+            Some(EscrowError::TooManyEntries.into())
+        );
+    }
+
+    #[test]
+    fn withdraw_builder_rejects_a_zero_amount() {
+        let builder = EscrowInstructionBuilder::new(Pubkey::new_unique());
+        let key = Pubkey::new_unique();
+
+        assert_eq!(
+            builder.withdraw(&key, &key, &key, &key, &key, None, 0, None).err(),
+            Some(EscrowError::ZeroAmount.into())
+        );
+        assert!(builder.withdraw(&key, &key, &key, &key, &key, None, 1, None).is_ok());
+    }
+
+    #[test]
+    fn builder_orders_accounts_as_the_processor_expects() {
+        let program_id = Pubkey::new_unique();
+        let builder = EscrowInstructionBuilder::new(program_id);
+        let pda = Pubkey::find_program_address(&[b"escrow"], &program_id).0;
+        let initializer = Pubkey::new_unique();
+        let temp_token_account = Pubkey::new_unique();
+        let withdrawer = Pubkey::new_unique();
+        let escrow_account = Pubkey::new_unique();
+        let token_account = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+
+        let params = InitEscrowParams::from_bytes(&params_bytes(1000)).unwrap();
+        let init = builder
+            .init_escrow(
+                &initializer,
+                &temp_token_account,
+                &withdrawer,
+                &escrow_account,
+                &mint,
+                params,
+            )
+            .unwrap();
+        assert_eq!(init.program_id, program_id);
+        assert_eq!(init.data, EscrowInstruction::InitEscrow(params).pack());
+        assert_eq!(
+            init.accounts[1..5],
+            [
+                AccountMeta::new(temp_token_account, false),
+                AccountMeta::new_readonly(withdrawer, false),
+                AccountMeta::new(escrow_account, false),
+                AccountMeta::new_readonly(spl_token::id(), false),
+            ]
+        );
+        assert_eq!(init.accounts[0].pubkey, initializer);
+        assert!(init.accounts[0].is_signer);
+
+        let withdraw = builder
+            .withdraw(
+                &withdrawer,
+                &token_account,
+                &temp_token_account,
+                &initializer,
+                &escrow_account,
+                None,
+                1000,
+                None,
+            )
+            .unwrap();
+        assert_eq!(withdraw.program_id, program_id);
+        assert_eq!(
+            withdraw.data,
+            EscrowInstruction::Withdraw {
+                amount: 1000,
+                preimage: None,
+            }
+            .pack()
+        );
+        assert_eq!(
+            withdraw.accounts[..7],
+            [
+                AccountMeta::new_readonly(withdrawer, true),
+                AccountMeta::new(token_account, false),
+                AccountMeta::new(temp_token_account, false),
+                AccountMeta::new(initializer, false),
+                AccountMeta::new(escrow_account, false),
+                AccountMeta::new_readonly(spl_token::id(), false),
+                AccountMeta::new_readonly(pda, false),
+            ]
+        );
+
+        let cancel = builder
+            .cancel_escrow(
+                &initializer,
+                &escrow_account,
+                &temp_token_account,
+                &token_account,
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(cancel.program_id, program_id);
+        assert_eq!(cancel.data, EscrowInstruction::CancelEscrow.pack());
+        assert_eq!(
+            cancel.accounts[..6],
+            [
+                AccountMeta::new(initializer, true),
+                AccountMeta::new(escrow_account, false),
+                AccountMeta::new(temp_token_account, false),
+                AccountMeta::new(token_account, false),
+                AccountMeta::new_readonly(spl_token::id(), false),
+                AccountMeta::new_readonly(pda, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn peek_tag_reads_the_leading_byte() {
+        assert_eq!(peek_tag(&[InstructionTag::Withdraw as u8, 1, 2]).unwrap(), 1);
+        assert_eq!(peek_tag(&[]).err(), Some(InvalidInstruction.into()));
+    }
+}
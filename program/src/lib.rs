@@ -1,7 +1,9 @@
 pub mod error;
+pub mod math;
 pub mod processor;
 pub mod state;
 pub mod instruction;
+pub mod token;
 
 #[cfg(not(feature = "no-entrypoint"))]
 pub mod entrypoint;
@@ -0,0 +1,73 @@
+//! Checked arithmetic for token amounts, lamports and basis points. Every helper reports
+//! overflow or underflow as [EscrowError::AmountOverflow](../error/enum.EscrowError.html), and
+//! results that do not fit back into a `u64` as `EscrowError::NumericConversion`.
+
+use std::convert::TryFrom;
+
+use crate::error::EscrowError;
+
+pub fn add(a: u64, b: u64) -> Result<u64, EscrowError> {
+    a.checked_add(b).ok_or(EscrowError::AmountOverflow)
+}
+
+pub fn sub(a: u64, b: u64) -> Result<u64, EscrowError> {
+    a.checked_sub(b).ok_or(EscrowError::AmountOverflow)
+}
+
+pub fn mul(a: u64, b: u64) -> Result<u64, EscrowError> {
+    a.checked_mul(b).ok_or(EscrowError::AmountOverflow)
+}
+
+/// `a * b / c` computed with a `u128` intermediate so the product cannot overflow
+pub fn mul_div(a: u64, b: u64, c: u64) -> Result<u64, EscrowError> {
+    let result = (a as u128 * b as u128)
+        .checked_div(c as u128)
+        .ok_or(EscrowError::AmountOverflow)?;
+    Ok(u64::try_from(result)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_checks_overflow() {
+        assert_eq!(add(2, 3).unwrap(), 5);
+        assert_eq!(add(u64::MAX, 0).unwrap(), u64::MAX);
+        assert!(matches!(add(u64::MAX, 1), Err(EscrowError::AmountOverflow)));
+    }
+
+    #[test]
+    fn sub_checks_underflow() {
+        assert_eq!(sub(5, 3).unwrap(), 2);
+        assert_eq!(sub(3, 3).unwrap(), 0);
+        assert!(matches!(sub(3, 4), Err(EscrowError::AmountOverflow)));
+    }
+
+    #[test]
+    fn mul_checks_overflow() {
+        assert_eq!(mul(6, 7).unwrap(), 42);
+        assert_eq!(mul(u64::MAX, 1).unwrap(), u64::MAX);
+        assert!(matches!(mul(u64::MAX, 2), Err(EscrowError::AmountOverflow)));
+    }
+
+    #[test]
+    fn mul_div_keeps_the_intermediate_product() {
+        assert_eq!(mul_div(1_000, 2_500, 10_000).unwrap(), 250);
+        assert_eq!(mul_div(u64::MAX, u64::MAX, u64::MAX).unwrap(), u64::MAX);
+        assert_eq!(mul_div(7, 1, 2).unwrap(), 3);
+        assert!(matches!(mul_div(1, 1, 0), Err(EscrowError::AmountOverflow)));
+    }
+
+    #[test]
+    fn mul_div_reports_results_past_u64() {
+        assert!(matches!(
+            mul_div(u64::MAX, 2, 1),
+            Err(EscrowError::NumericConversion)
+        ));
+        assert!(matches!(
+            EscrowError::from(u64::try_from(u128::MAX).unwrap_err()),
+            EscrowError::NumericConversion
+        ));
+    }
+}
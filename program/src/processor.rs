@@ -1,17 +1,445 @@
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
+    bpf_loader_upgradeable::{self, UpgradeableLoaderState},
     entrypoint::ProgramResult,
     msg,
-    program::{invoke, invoke_signed},
+    ed25519_program,
+    hash::hash,
+    program::{invoke, invoke_signed, set_return_data},
     program_error::ProgramError,
+    program_option::COption,
     program_pack::{IsInitialized, Pack},
     pubkey::Pubkey,
-    sysvar::{rent::Rent, Sysvar},
+    system_instruction,
+    sysvar::{
+        clock::{self, Clock},
+        instructions,
+        rent::Rent,
+        Sysvar,
+    },
 };
 
-use spl_token::state::Account as TokenAccount;
+use arrayref::{array_ref, array_refs};
+use spl_associated_token_account::instruction::create_associated_token_account_idempotent;
+use spl_token::state::{Account as TokenAccount, Mint};
 
-use crate::{error::EscrowError, instruction::EscrowInstruction, state::Escrow};
+#[cfg(feature = "escrow-limit")]
+use std::convert::TryFrom;
+
+#[cfg(feature = "escrow-limit")]
+use arrayref::array_mut_ref;
+
+#[cfg(feature = "escrow-limit")]
+use crate::state::{COUNTER_LEN, MAX_ESCROWS_PER_USER};
+
+use crate::{
+    error::EscrowError,
+    math,
+    instruction::{authorization_message, EscrowInstruction, InitEscrowParams},
+    state::{Escrow, EscrowStatus, REGISTRY_LEN, TEMP_TOKEN_ACCOUNT_LEN, TOTAL_BPS},
+    token::assert_usable_token_account,
+};
+
+/// Logs the remaining compute units, only when built with the `debug-compute` feature
+#[inline(always)]
+fn log_compute_units() {
+    #[cfg(feature = "debug-compute")]
+    solana_program::log::sol_log_compute_units();
+}
+
+/// Fails with `EscrowAlreadyClosed` if a close earlier in the transaction already emptied and
+/// zeroed `escrow_account`
+fn assert_not_closed(escrow_account: &AccountInfo) -> ProgramResult {
+    if escrow_account.lamports() == 0
+        || escrow_account.try_borrow_data()?.iter().all(|&byte| byte == 0)
+    {
+        return Err(EscrowError::EscrowAlreadyClosed.into());
+    }
+    Ok(())
+}
+
+/// Fails with `IncorrectProgramId` unless `account` is owned by `owner`
+fn assert_owned_by(account: &AccountInfo, owner: &Pubkey) -> ProgramResult {
+    if account.owner != owner {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+    Ok(())
+}
+
+/// Moves every lamport held by `from` into `to`, leaving `from` with none. `from` must be owned
+/// by this program and still cover its own rent, anything less means its balance was tampered
+/// with.
+fn credit_lamports(from: &AccountInfo, to: &AccountInfo, rent: &Rent) -> ProgramResult {
+    let amount = from.lamports();
+    if amount < rent.minimum_balance(from.data_len()) {
+        return Err(EscrowError::NotRentExempt.into());
+    }
+    **to.try_borrow_mut_lamports()? = math::add(to.lamports(), amount)?;
+    **from.try_borrow_mut_lamports()? = math::sub(from.lamports(), amount)?;
+    if from.lamports() != 0 {
+        return Err(EscrowError::UnexpectedLamports.into());
+    }
+    Ok(())
+}
+
+/// The treasury PDA derived from `[b"treasury"]` that a closing escrow's rent goes to, passed
+/// after the escrow counter for escrows initialized with `rent_to_treasury`
+fn treasury_account<'a, 'b>(
+    escrow_info: &Escrow,
+    account_info_iter: &mut std::slice::Iter<'b, AccountInfo<'a>>,
+    program_id: &Pubkey,
+) -> Result<Option<&'b AccountInfo<'a>>, ProgramError> {
+    if !escrow_info.rent_to_treasury {
+        return Ok(None);
+    }
+
+    let treasury = next_account_info(account_info_iter)?;
+    let (treasury_pda, _treasury_nonce) = Pubkey::find_program_address(&[b"treasury"], program_id);
+    if *treasury.key != treasury_pda {
+        return Err(ProgramError::InvalidSeeds);
+    }
+    Ok(Some(treasury))
+}
+
+/// Fails unless `authority` signed and is the upgrade authority recorded in `program_data`, this
+/// program's data account under the upgradeable BPF loader
+fn assert_upgrade_authority(
+    authority: &AccountInfo,
+    program_data: &AccountInfo,
+    program_id: &Pubkey,
+) -> ProgramResult {
+    if !authority.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let (program_data_address, _program_data_nonce) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    if *program_data.key != program_data_address {
+        return Err(ProgramError::InvalidSeeds);
+    }
+    assert_owned_by(program_data, &bpf_loader_upgradeable::id())?;
+
+    // `UpgradeableLoaderState::ProgramData`: the u32 variant tag 3, the u64 slot and the
+    // optional upgrade authority
+    let data = program_data.try_borrow_data()?;
+    let metadata = data
+        .get(..UpgradeableLoaderState::size_of_programdata_metadata())
+        .ok_or(ProgramError::InvalidAccountData)?;
+    let (tag, _slot, authority_option, upgrade_authority) = array_refs![
+        array_ref![metadata, 0, 45],
+        4,
+        8,
+        1,
+        32
+    ];
+    if u32::from_le_bytes(*tag) != 3 {
+        return Err(ProgramError::InvalidAccountData);
+    }
+    // a program made immutable has no authority left to withdraw
+    if authority_option[0] != 1 || upgrade_authority != authority.key.as_ref() {
+        return Err(EscrowError::InvalidAuthority.into());
+    }
+    Ok(())
+}
+
+/// The registry entry of an escrow initialized with `use_registry`, passed after the treasury.
+/// Only this program writes entries and it fills them with the escrow's pubkey, so an entry
+/// holding `escrow_account` is the one derived for it.
+fn registry_entry<'a, 'b>(
+    escrow_info: &Escrow,
+    escrow_account: &Pubkey,
+    account_info_iter: &mut std::slice::Iter<'b, AccountInfo<'a>>,
+    program_id: &Pubkey,
+) -> Result<Option<&'b AccountInfo<'a>>, ProgramError> {
+    if !escrow_info.registered {
+        return Ok(None);
+    }
+
+    let registry_account = next_account_info(account_info_iter)?;
+    assert_owned_by(registry_account, program_id)?;
+    if registry_account.data_len() != REGISTRY_LEN
+        || registry_account.try_borrow_data()?[..] != escrow_account.as_ref()[..]
+    {
+        return Err(ProgramError::InvalidSeeds);
+    }
+    Ok(Some(registry_account))
+}
+
+/// Creates the PDA `account` for `seeds` with `space` bytes owned by `owner`, `payer` topping up
+/// its rent. Unlike `create_account` this still works when lamports were sent to the address
+/// beforehand, so nobody can block a PDA by funding it.
+fn create_pda_account<'a>(
+    payer: &AccountInfo<'a>,
+    account: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    space: usize,
+    owner: &Pubkey,
+    rent: &Rent,
+    seeds: &[&[u8]],
+) -> ProgramResult {
+    let missing_lamports = rent.minimum_balance(space).saturating_sub(account.lamports());
+    if missing_lamports > 0 {
+        let transfer_ix = system_instruction::transfer(payer.key, account.key, missing_lamports);
+        msg!("Calling the system program to fund the account...");
+        log_compute_units();
+        invoke(
+            &transfer_ix,
+            &[payer.clone(), account.clone(), system_program.clone()],
+        )?;
+        log_compute_units();
+    }
+
+    let allocate_ix = system_instruction::allocate(account.key, space as u64);
+    msg!("Calling the system program to allocate the account...");
+    log_compute_units();
+    invoke_signed(&allocate_ix, &[account.clone(), system_program.clone()], &[seeds])?;
+    log_compute_units();
+
+    let assign_ix = system_instruction::assign(account.key, owner);
+    msg!("Calling the system program to assign the account...");
+    log_compute_units();
+    invoke_signed(&assign_ix, &[account.clone(), system_program.clone()], &[seeds])?;
+    log_compute_units();
+    Ok(())
+}
+
+/// Counts a new escrow against the initializer's limit, creating their counter on first use
+#[cfg(feature = "escrow-limit")]
+fn increment_escrow_count<'a>(
+    initializer: &AccountInfo<'a>,
+    counter_account: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    rent: &Rent,
+    program_id: &Pubkey,
+) -> ProgramResult {
+    let (counter_pda, counter_nonce) =
+        Pubkey::find_program_address(&[b"counter", initializer.key.as_ref()], program_id);
+    if *counter_account.key != counter_pda {
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    if counter_account.owner != program_id {
+        msg!("Creating the escrow counter...");
+        create_pda_account(
+            initializer,
+            counter_account,
+            system_program,
+            COUNTER_LEN,
+            program_id,
+            rent,
+            &[&b"counter"[..], initializer.key.as_ref(), &[counter_nonce]],
+        )?;
+    }
+
+    let mut counter_data = counter_account.try_borrow_mut_data()?;
+    let count_dst = array_mut_ref![counter_data, 0, COUNTER_LEN];
+    let count = u32::from_le_bytes(*count_dst);
+    if count >= MAX_ESCROWS_PER_USER {
+        return Err(EscrowError::TooManyEscrows.into());
+    }
+    *count_dst = u32::try_from(math::add(count as u64, 1)?)
+        .map_err(EscrowError::from)?
+        .to_le_bytes();
+    Ok(())
+}
+
+/// Releases one escrow from the initializer's limit once it is closed
+#[cfg(feature = "escrow-limit")]
+fn decrement_escrow_count(
+    initializer_pubkey: &Pubkey,
+    counter_account: &AccountInfo,
+    program_id: &Pubkey,
+) -> ProgramResult {
+    let (counter_pda, _counter_nonce) =
+        Pubkey::find_program_address(&[b"counter", initializer_pubkey.as_ref()], program_id);
+    if *counter_account.key != counter_pda || counter_account.owner != program_id {
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    let mut counter_data = counter_account.try_borrow_mut_data()?;
+    let count_dst = array_mut_ref![counter_data, 0, COUNTER_LEN];
+    // escrows opened before the counter existed were never counted
+    *count_dst = u32::from_le_bytes(*count_dst).saturating_sub(1).to_le_bytes();
+    Ok(())
+}
+
+/// Checks that the milestones in use release the whole deposit
+fn assert_valid_milestones(params: &InitEscrowParams) -> ProgramResult {
+    if params.milestone_count > 0 {
+        let total_bps = params.milestones[..params.milestone_count as usize]
+            .iter()
+            .try_fold(0, |bps, milestone| math::add(bps, milestone.fraction_bps as u64))?;
+        if total_bps != TOTAL_BPS as u64 {
+            return Err(EscrowError::InvalidMilestones.into());
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `preimage` hashes to the escrow's hashlock. Escrows without a hashlock pass
+/// unconditionally.
+fn assert_preimage(escrow_info: &Escrow, preimage: Option<&[u8; 32]>) -> ProgramResult {
+    if escrow_info.hashlock == [0; 32] {
+        return Ok(());
+    }
+
+    match preimage {
+        Some(preimage) if hash(preimage).to_bytes() == escrow_info.hashlock => Ok(()),
+        _ => Err(EscrowError::InvalidPreimage.into()),
+    }
+}
+
+/// Checks that `receive_account` belongs to `taker` when the escrow asks for it
+fn assert_receive_owner(
+    escrow_info: &Escrow,
+    receive_account: &AccountInfo,
+    taker: &Pubkey,
+) -> ProgramResult {
+    if escrow_info.require_receive_owner
+        && TokenAccount::unpack(&receive_account.try_borrow_data()?)?.owner != *taker
+    {
+        return Err(EscrowError::ReceiveAccountOwnerMismatch.into());
+    }
+    Ok(())
+}
+
+/// Checks that the instruction before this one verified the escrow authority's signature over
+/// the withdraw. Escrows without an authority pass unconditionally.
+fn assert_withdraw_authorized(
+    escrow_info: &Escrow,
+    escrow_account: &Pubkey,
+    taker: &Pubkey,
+    amount: u64,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    if escrow_info.authority_pubkey == Pubkey::default() {
+        return Ok(());
+    }
+
+    // only escrows with an authority need the instructions sysvar, everyone else may leave it out
+    let instructions_sysvar = match accounts
+        .iter()
+        .find(|account| instructions::check_id(account.key))
+    {
+        Some(instructions_sysvar) => instructions_sysvar,
+        None => {
+            msg!("The escrow has an authority, the instructions sysvar must be passed");
+            return Err(EscrowError::InvalidAuthorization.into());
+        }
+    };
+    let verify_ix = instructions::get_instruction_relative(-1, instructions_sysvar)
+        .map_err(|_| EscrowError::InvalidAuthorization)?;
+    if verify_ix.program_id != ed25519_program::id() {
+        return Err(EscrowError::InvalidAuthorization.into());
+    }
+
+    // a single signature whose offsets all point into the ed25519 instruction's own data
+    let data = &verify_ix.data;
+    if data.len() < 16 || data[0] != 1 {
+        return Err(EscrowError::InvalidAuthorization.into());
+    }
+    let offset = |at: usize| u16::from_le_bytes([data[at], data[at + 1]]) as usize;
+    if [offset(4), offset(8), offset(14)].iter().any(|&index| index != u16::MAX as usize) {
+        return Err(EscrowError::InvalidAuthorization.into());
+    }
+    let public_key = data.get(offset(6)..offset(6) + 32);
+    let message = data.get(offset(10)..offset(10) + offset(12));
+    let expected_message =
+        authorization_message(escrow_account, taker, amount, escrow_info.withdraw_nonce);
+
+    if public_key != Some(escrow_info.authority_pubkey.as_ref())
+        || message != Some(&expected_message[..])
+    {
+        return Err(EscrowError::InvalidAuthorization.into());
+    }
+    Ok(())
+}
+
+/// Runs every check the escrow puts on `taker` withdrawing `amount` right now and returns the
+/// current unix timestamp
+fn assert_withdrawable(
+    escrow_info: &Escrow,
+    escrow_account: &Pubkey,
+    taker: &Pubkey,
+    amount: u64,
+    accounts: &[AccountInfo],
+) -> Result<i64, ProgramError> {
+    assert_withdraw_authorized(escrow_info, escrow_account, taker, amount, accounts)?;
+
+    let now = current_clock(accounts)?.unix_timestamp;
+    if escrow_info.is_expired(now) {
+        return Err(EscrowError::EscrowExpired.into());
+    }
+
+    if escrow_info.last_withdraw_unix_timestamp != 0
+        && now.saturating_sub(escrow_info.last_withdraw_unix_timestamp)
+            < escrow_info.withdraw_cooldown_secs as i64
+    {
+        return Err(EscrowError::WithdrawCooldownActive.into());
+    }
+
+    if amount > escrow_info.deposited_amount {
+        return Err(EscrowError::InsufficientEscrowBalance.into());
+    }
+
+    if amount > escrow_info.claimable_amount(now) {
+        return Err(EscrowError::ExpectedAmountMismatch.into());
+    }
+
+    if math::sub(escrow_info.deposited_amount, amount)? < escrow_info.reserve_amount {
+        return Err(EscrowError::BelowReserve.into());
+    }
+
+    if escrow_info.full_only && amount < escrow_info.deposited_amount {
+        return Err(EscrowError::PartialNotAllowed.into());
+    }
+    Ok(now)
+}
+
+/// Logs the single `ESCROW_CREATED` line indexers parse after a successful init
+fn log_escrow_created(escrow: &Pubkey, mint: &Pubkey, withdrawer: &Pubkey, amount: u64) {
+    msg!(
+        "ESCROW_CREATED pubkey={} mint={} withdrawer={} amount={}",
+        escrow,
+        mint,
+        withdrawer,
+        amount
+    );
+}
+
+/// Sets a withdraw's return data, the `closed` flag followed by the escrow's reference id and
+/// its status
+fn set_withdraw_return_data(status: EscrowStatus, escrow_info: &Escrow) {
+    let mut data = [0u8; 10];
+    data[0] = (status == EscrowStatus::Closed) as u8;
+    data[1..9].copy_from_slice(&escrow_info.reference_id.to_le_bytes());
+    data[9] = status as u8;
+    set_return_data(&data);
+}
+
+/// The clock sysvar passed as the last account if there is one, else the one from `Clock::get`
+fn current_clock(accounts: &[AccountInfo]) -> Result<Clock, ProgramError> {
+    match accounts.last() {
+        Some(account) if clock::check_id(account.key) => Clock::from_account_info(account),
+        _ => Clock::get(),
+    }
+}
+
+/// What an instruction did to the escrow it targeted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcessOutcome {
+    /// A new escrow was initialized, by `InitEscrow` or as the second half of a `SplitEscrow`
+    Initialized { escrow: Pubkey },
+    /// A partial withdraw left `remaining` deposited
+    Withdrawn { remaining: u64 },
+    /// The escrow was emptied and closed
+    Closed,
+    /// The escrow stays open with `remaining` deposited after a merge, a drain, a seal or a
+    /// partial cancel
+    Updated { remaining: u64 },
+    /// Nothing was changed
+    Unchanged,
+}
 
 pub struct Processor;
 impl Processor {
@@ -20,25 +448,109 @@ impl Processor {
         accounts: &[AccountInfo], 
         instruction_data: &[u8]
     ) -> ProgramResult {
+        Self::process_with_outcome(program_id, accounts, instruction_data).map(|_| ())
+    }
+
+    /// Same as [process](#method.process) but also reports what the instruction did to the
+    /// escrow
+    pub fn process_with_outcome(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        instruction_data: &[u8],
+    ) -> Result<ProcessOutcome, ProgramError> {
         let instruction = EscrowInstruction::unpack(instruction_data)?;
 
         match instruction {
-            EscrowInstruction::InitEscrow { amount } => {
+            EscrowInstruction::InitEscrow(params) => {
                 msg!("Instruction: InitEscrow");
-                Self::process_init_escrow(accounts, amount, program_id)
+                Self::process_init_escrow(accounts, params, program_id)
             }
-            EscrowInstruction::Withdraw { amount } => {
+            EscrowInstruction::Withdraw { amount, preimage } => {
                 msg!("Instruction: Withdraw");
-                Self::process_withdraw(accounts, amount, program_id)
+                Self::process_withdraw(accounts, amount, preimage, program_id)
+            }
+            EscrowInstruction::SplitEscrow { amount } => {
+                msg!("Instruction: SplitEscrow");
+                Self::process_split_escrow(accounts, amount, program_id)
+            }
+            EscrowInstruction::MergeEscrow => {
+                msg!("Instruction: MergeEscrow");
+                Self::process_merge_escrow(accounts, program_id)
+            }
+            EscrowInstruction::Drain => {
+                msg!("Instruction: Drain");
+                Self::process_drain(accounts, program_id)
+            }
+            EscrowInstruction::Decline => {
+                msg!("Instruction: Decline");
+                Self::process_decline(accounts, program_id)
+            }
+            EscrowInstruction::CancelEscrow => {
+                msg!("Instruction: CancelEscrow");
+                Self::process_cancel_escrow(accounts, program_id)
+            }
+            EscrowInstruction::AssertState {
+                expected_withdrawer,
+                min_remaining,
+            } => {
+                msg!("Instruction: AssertState");
+                Self::process_assert_state(accounts, &expected_withdrawer, min_remaining, program_id)
+            }
+            EscrowInstruction::WithdrawSplit { allocations } => {
+                msg!("Instruction: WithdrawSplit");
+                Self::process_withdraw_split(accounts, &allocations, program_id)
+            }
+            EscrowInstruction::StateHash => {
+                msg!("Instruction: StateHash");
+                Self::process_state_hash(accounts, program_id)
+            }
+            EscrowInstruction::Seal => {
+                msg!("Instruction: Seal");
+                Self::process_seal(accounts, program_id)
+            }
+            EscrowInstruction::CancelPartial { amount } => {
+                msg!("Instruction: CancelPartial");
+                Self::process_cancel_partial(accounts, amount, program_id)
+            }
+            EscrowInstruction::WithdrawTreasury { amount } => {
+                msg!("Instruction: WithdrawTreasury");
+                Self::process_withdraw_treasury(accounts, amount, program_id)
             }
         }
     }
 
     fn process_init_escrow(
         accounts: &[AccountInfo],
-        amount: u64,
+        params: InitEscrowParams,
         program_id: &Pubkey,
-    ) -> ProgramResult {
+    ) -> Result<ProcessOutcome, ProgramError> {
+        let InitEscrowParams {
+            amount,
+            freeze,
+            reserve_amount,
+            require_wallet_withdrawer,
+            use_registry,
+            vault,
+            milestone_count,
+            milestones,
+            lamports,
+            full_only,
+            authority,
+            dust_threshold,
+            withdraw_cooldown_secs,
+            rent_to_treasury,
+            require_receive_owner,
+            hashlock,
+            expiry_unix_timestamp,
+            require_exact_rent,
+            reference_id,
+            create_receive_account,
+            dust_collector,
+        } = params;
+        if lamports {
+            return Self::process_init_lamport_escrow(accounts, params, program_id);
+        }
+
         let account_info_iter = &mut accounts.iter();
         let initializer = next_account_info(account_info_iter)?;
 
@@ -48,56 +560,310 @@ impl Processor {
         
         let temp_token_account = next_account_info(account_info_iter)?;
 
+        assert_usable_token_account(temp_token_account, None, Some(initializer.key))?;
+        if TokenAccount::unpack(&temp_token_account.try_borrow_data()?)?.amount == 0 {
+            return Err(EscrowError::EmptyTempAccount.into());
+        }
+
         let withdrawer_account = next_account_info(account_info_iter)?;
 
+        if withdrawer_account.is_signer || withdrawer_account.is_writable {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        if require_wallet_withdrawer && !withdrawer_account.key.is_on_curve() {
+            return Err(EscrowError::WithdrawerMustBeWallet.into());
+        }
+
         let escrow_account = next_account_info(account_info_iter)?;
-        let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+        let (pda, nonce) = Pubkey::find_program_address(&[b"escrow"], program_id);
+
+        // the PDA signs for every temp token account, it cannot also be an escrow
+        if *escrow_account.key == pda {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let rent = &Rent::get()?;
 
-        if !rent.is_exempt(escrow_account.lamports(), escrow_account.data_len()) {
+        if rent.minimum_balance(escrow_account.data_len()) == 0
+            || !rent.is_exempt(escrow_account.lamports(), escrow_account.data_len())
+        {
             return Err(EscrowError::NotRentExempt.into());
         }
 
+        if require_exact_rent
+            && escrow_account.lamports() > rent.minimum_balance(escrow_account.data_len())
+        {
+            return Err(EscrowError::UnexpectedLamports.into());
+        }
+
+        assert_owned_by(escrow_account, program_id)?;
+
         let mut escrow_info = Escrow::unpack_unchecked(&escrow_account.try_borrow_data()?)?;
         if escrow_info.is_initialized() {
             return Err(ProgramError::AccountAlreadyInitialized);
         }
 
+        // stale bytes in an uninitialized account would otherwise leak into unset fields, and
+        // only escrows of older layouts may be shorter than the current one
+        if escrow_account.data_len() != Escrow::LEN
+            || escrow_account.try_borrow_data()?.iter().any(|&byte| byte != 0)
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
         escrow_info.is_initialized = true;
         escrow_info.initializer_pubkey = *initializer.key;
         escrow_info.temp_token_account_pubkey = *temp_token_account.key;
         escrow_info.withdrawer_pubkey = *withdrawer_account.key;
         escrow_info.deposited_amount = amount;
+        escrow_info.freeze_mode = freeze;
+        escrow_info.full_only = full_only;
+        escrow_info.authority_pubkey = authority;
+        escrow_info.dust_threshold = dust_threshold;
+        escrow_info.dust_collector_pubkey = dust_collector;
+        escrow_info.withdraw_cooldown_secs = withdraw_cooldown_secs;
+        escrow_info.rent_to_treasury = rent_to_treasury;
+        escrow_info.require_receive_owner = require_receive_owner;
+        escrow_info.hashlock = hashlock;
+        escrow_info.expiry_unix_timestamp = expiry_unix_timestamp;
+        escrow_info.reference_id = reference_id;
+        escrow_info.create_receive_account = create_receive_account;
+        escrow_info.reserve_amount = reserve_amount;
+        escrow_info.registered = use_registry;
 
-        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
-        let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], program_id);
+        assert_valid_milestones(&params)?;
+        escrow_info.milestone_count = milestone_count;
+        escrow_info.milestones = milestones;
 
         let token_program = next_account_info(account_info_iter)?;
-        let owner_change_ix = spl_token::instruction::set_authority(
-            token_program.key,
-            temp_token_account.key,
-            Some(&pda),
-            spl_token::instruction::AuthorityType::AccountOwner,
-            initializer.key,
-            &[&initializer.key],
-        )?;
 
-        msg!("Calling the token program to transfer token account ownership...");
-        invoke(
-            &owner_change_ix,
-            &[
-                temp_token_account.clone(),
-                initializer.clone(),
-                token_program.clone(),
-            ],
-        )?;
-        Ok(())
+        // the temp account has to belong to the token program the CPIs below are sent to
+        if *token_program.key != spl_token::id() || temp_token_account.owner != token_program.key {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        if vault {
+            let vault_account = next_account_info(account_info_iter)?;
+            let mint_account = next_account_info(account_info_iter)?;
+            let system_program = next_account_info(account_info_iter)?;
+            if TokenAccount::unpack(&temp_token_account.try_borrow_data()?)?.mint
+                != *mint_account.key
+            {
+                return Err(ProgramError::InvalidAccountData);
+            }
+
+            // a vault of its own per escrow, so no other escrow can ever reach its tokens
+            let vault_seeds: &[&[u8]] = &[b"vault", escrow_account.key.as_ref()];
+            let (vault_pda, vault_nonce) = Pubkey::find_program_address(vault_seeds, program_id);
+            if *vault_account.key != vault_pda {
+                return Err(ProgramError::InvalidSeeds);
+            }
+
+            msg!("Creating the vault...");
+            create_pda_account(
+                initializer,
+                vault_account,
+                system_program,
+                TEMP_TOKEN_ACCOUNT_LEN,
+                token_program.key,
+                rent,
+                &[vault_seeds, &[&[vault_nonce]]].concat(),
+            )?;
+            let init_vault_ix = spl_token::instruction::initialize_account3(
+                token_program.key,
+                vault_account.key,
+                mint_account.key,
+                &pda,
+            )?;
+            msg!("Calling the token program to initialize the vault...");
+            log_compute_units();
+            invoke(
+                &init_vault_ix,
+                &[
+                    vault_account.clone(),
+                    mint_account.clone(),
+                    token_program.clone(),
+                ],
+            )?;
+            log_compute_units();
+
+            let transfer_to_vault_ix = spl_token::instruction::transfer(
+                token_program.key,
+                temp_token_account.key,
+                vault_account.key,
+                initializer.key,
+                &[initializer.key],
+                amount,
+            )?;
+            msg!("Calling the token program to transfer {} tokens into the vault...", amount);
+            log_compute_units();
+            invoke(
+                &transfer_to_vault_ix,
+                &[
+                    temp_token_account.clone(),
+                    vault_account.clone(),
+                    initializer.clone(),
+                    token_program.clone(),
+                ],
+            )?;
+            log_compute_units();
+            escrow_info.temp_token_account_pubkey = *vault_account.key;
+        } else if freeze {
+            let mint_account = next_account_info(account_info_iter)?;
+            let pda_account = next_account_info(account_info_iter)?;
+            let temp_token_account_info =
+                TokenAccount::unpack(&temp_token_account.try_borrow_data()?)?;
+            let mint_info = Mint::unpack(&mint_account.try_borrow_data()?)?;
+
+            if temp_token_account_info.mint != *mint_account.key {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            if mint_info.freeze_authority != COption::Some(pda) {
+                return Err(EscrowError::InvalidFreezeAuthority.into());
+            }
+
+            let approve_ix = spl_token::instruction::approve(
+                token_program.key,
+                temp_token_account.key,
+                &pda,
+                initializer.key,
+                &[initializer.key],
+                amount,
+            )?;
+            msg!("Calling the token program to approve the PDA as delegate...");
+            log_compute_units();
+            invoke(
+                &approve_ix,
+                &[
+                    temp_token_account.clone(),
+                    initializer.clone(),
+                    token_program.clone(),
+                ],
+            )?;
+            log_compute_units();
+
+            let freeze_ix = spl_token::instruction::freeze_account(
+                token_program.key,
+                temp_token_account.key,
+                mint_account.key,
+                &pda,
+                &[&pda],
+            )?;
+            msg!("Calling the token program to freeze the temp token account...");
+            log_compute_units();
+            invoke_signed(
+                &freeze_ix,
+                &[
+                    temp_token_account.clone(),
+                    mint_account.clone(),
+                    pda_account.clone(),
+                    token_program.clone(),
+                ],
+                &[&[&b"escrow"[..], &[nonce]]],
+            )?;
+            log_compute_units();
+        } else {
+            let owner_change_ix = spl_token::instruction::set_authority(
+                token_program.key,
+                temp_token_account.key,
+                Some(&pda),
+                spl_token::instruction::AuthorityType::AccountOwner,
+                initializer.key,
+                &[initializer.key],
+            )?;
+
+            msg!("Calling the token program to transfer token account ownership...");
+            log_compute_units();
+            invoke(
+                &owner_change_ix,
+                &[
+                    temp_token_account.clone(),
+                    initializer.clone(),
+                    token_program.clone(),
+                ],
+            )?;
+            log_compute_units();
+        }
+
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        if use_registry {
+            let registry_account = next_account_info(account_info_iter)?;
+            let system_program = next_account_info(account_info_iter)?;
+            let mint = TokenAccount::unpack(&temp_token_account.try_borrow_data()?)?.mint;
+            let registry_seeds: &[&[u8]] = &[
+                b"registry",
+                initializer.key.as_ref(),
+                mint.as_ref(),
+                withdrawer_account.key.as_ref(),
+            ];
+            let (registry_pda, registry_nonce) =
+                Pubkey::find_program_address(registry_seeds, program_id);
+
+            if *registry_account.key != registry_pda {
+                return Err(ProgramError::InvalidSeeds);
+            }
+            if registry_account.owner == program_id {
+                return Err(EscrowError::DuplicateEscrow.into());
+            }
+
+            msg!("Creating the registry entry...");
+            create_pda_account(
+                initializer,
+                registry_account,
+                system_program,
+                REGISTRY_LEN,
+                program_id,
+                rent,
+                &[registry_seeds, &[&[registry_nonce]]].concat(),
+            )?;
+            registry_account
+                .try_borrow_mut_data()?
+                .copy_from_slice(escrow_account.key.as_ref());
+        }
+
+        #[cfg(feature = "escrow-limit")]
+        {
+            let counter_account = next_account_info(account_info_iter)?;
+            let system_program = next_account_info(account_info_iter)?;
+            increment_escrow_count(initializer, counter_account, system_program, rent, program_id)?;
+        }
+
+        log_escrow_created(
+            escrow_account.key,
+            &TokenAccount::unpack(&temp_token_account.try_borrow_data()?)?.mint,
+            withdrawer_account.key,
+            amount,
+        );
+        set_return_data(
+            &[escrow_account.key.as_ref(), &reference_id.to_le_bytes()].concat(),
+        );
+        Ok(ProcessOutcome::Initialized {
+            escrow: *escrow_account.key,
+        })
     }
 
     fn process_withdraw(
         accounts: &[AccountInfo],
         amount_to_withdraw: u64,
+        preimage: Option<[u8; 32]>,
         program_id: &Pubkey,
-    ) -> ProgramResult {
+    ) -> Result<ProcessOutcome, ProgramError> {
+        if amount_to_withdraw == 0 {
+            return Err(EscrowError::ZeroAmount.into());
+        }
+
+        // lamport escrows hold the funds themselves and are passed in place of a token account
+        if accounts.get(1).is_some_and(|account| account.owner == program_id) {
+            return Self::process_withdraw_lamports(
+                accounts,
+                amount_to_withdraw,
+                preimage,
+                program_id,
+            );
+        }
+
         let account_info_iter = &mut accounts.iter();
         let taker = next_account_info(account_info_iter)?;
 
@@ -119,9 +885,29 @@ impl Processor {
         let initializers_main_account = next_account_info(account_info_iter)?;
         let escrow_account = next_account_info(account_info_iter)?;
 
+        // the escrow data is borrowed again mutably further down, a duplicate would fail there
+        // with an opaque borrow error
+        if [
+            taker,
+            takers_token_to_receive_account,
+            pdas_temp_token_account,
+            initializers_main_account,
+        ]
+        .iter()
+        .any(|account| account.key == escrow_account.key)
+            || *escrow_account.key == pda
+        {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        assert_not_closed(escrow_account)?;
+        assert_owned_by(escrow_account, program_id)?;
+
         let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
 
-        if escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key {
+        if escrow_info.lamport_mode
+            || escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key
+        {
             return Err(ProgramError::InvalidAccountData);
         }
 
@@ -135,50 +921,124 @@ impl Processor {
 
         let token_program = next_account_info(account_info_iter)?;
 
+        if *token_program.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
         let pda_account = next_account_info(account_info_iter)?;
 
+        // a frozen-mode temp account is still owned by the initializer and only usable once thawed
+        if !escrow_info.freeze_mode {
+            assert_usable_token_account(pdas_temp_token_account, None, Some(&pda))?;
+        }
 
-        // withdraw amount check
-        // already checked in line 115 
-        // if amount > escrow_info.deposited_amount{
-        //     return Err(ProgramError::InvalidAccountData);
-        // }
-        // escrow_info.deposited_amount or pdas_temp_token_account_info.amount can be used i think. Same huna parne ho as per my code.
-        if amount_to_withdraw < escrow_info.deposited_amount{
-            let remaining_amount = escrow_info.deposited_amount - amount_to_withdraw;
-            let transfer_to_taker_ix = spl_token::instruction::transfer(
+        let mint_account = if escrow_info.freeze_mode {
+            let mint_account = next_account_info(account_info_iter)?;
+            let thaw_ix = spl_token::instruction::thaw_account(
                 token_program.key,
                 pdas_temp_token_account.key,
-                takers_token_to_receive_account.key,
+                mint_account.key,
                 &pda,
                 &[&pda],
-                amount_to_withdraw,
             )?;
-            msg!("Calling the token program to transfer {} tokens to the taker...", amount_to_withdraw);
+            msg!("Calling the token program to thaw the temp token account...");
+            log_compute_units();
             invoke_signed(
-                &transfer_to_taker_ix,
+                &thaw_ix,
                 &[
                     pdas_temp_token_account.clone(),
-                    takers_token_to_receive_account.clone(),
+                    mint_account.clone(),
                     pda_account.clone(),
                     token_program.clone(),
                 ],
                 &[&[&b"escrow"[..], &[nonce]]],
             )?;
-            // store new info into escro account
-            escrow_info.deposited_amount = remaining_amount;
-            Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
-        }
-        else{
-            let transfer_to_taker_ix = spl_token::instruction::transfer(
+            log_compute_units();
+            Some(mint_account)
+        } else {
+            None
+        };
+
+        if escrow_info.create_receive_account {
+            let mint_account = next_account_info(account_info_iter)?;
+            let system_program = next_account_info(account_info_iter)?;
+            let associated_token_program = next_account_info(account_info_iter)?;
+
+            if *mint_account.key != pdas_temp_token_account_info.mint {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            if *associated_token_program.key != spl_associated_token_account::id() {
+                return Err(ProgramError::IncorrectProgramId);
+            }
+
+            // the associated token program checks the address and leaves an existing account be
+            let create_receive_account_ix = create_associated_token_account_idempotent(
+                taker.key,
+                taker.key,
+                mint_account.key,
                 token_program.key,
-                pdas_temp_token_account.key,
-                takers_token_to_receive_account.key,
-                &pda,
-                &[&pda],
-                pdas_temp_token_account_info.amount,
-            )?;
-            msg!("Calling the token program to transfer all tokens to the taker...");
+            );
+            msg!("Calling the associated token program to create the taker's token account...");
+            log_compute_units();
+            invoke(
+                &create_receive_account_ix,
+                &[
+                    taker.clone(),
+                    takers_token_to_receive_account.clone(),
+                    mint_account.clone(),
+                    system_program.clone(),
+                    token_program.clone(),
+                    associated_token_program.clone(),
+                ],
+            )?;
+            log_compute_units();
+        }
+
+        // checked only now, as the receive account may not have existed before the call above
+        assert_usable_token_account(
+            takers_token_to_receive_account,
+            Some(&pdas_temp_token_account_info.mint),
+            None,
+        )?;
+
+        // paying the PDA's own accounts would only shuffle tokens while still decrementing the
+        // deposited amount
+        if takers_token_to_receive_account.key == pdas_temp_token_account.key
+            || TokenAccount::unpack(&takers_token_to_receive_account.try_borrow_data()?)?.owner
+                == pda
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        assert_receive_owner(&escrow_info, takers_token_to_receive_account, taker.key)?;
+
+        let now = assert_withdrawable(
+            &escrow_info,
+            escrow_account.key,
+            taker.key,
+            amount_to_withdraw,
+            accounts,
+        )?;
+        assert_preimage(&escrow_info, preimage.as_ref())?;
+
+        // withdraw amount check
+        // already checked in line 115 
+        // if amount > escrow_info.deposited_amount{
+        //     return Err(ProgramError::InvalidAccountData);
+        // }
+        // escrow_info.deposited_amount or pdas_temp_token_account_info.amount can be used i think. Same huna parne ho as per my code.
+        if amount_to_withdraw < escrow_info.deposited_amount{
+            let remaining_amount = math::sub(escrow_info.deposited_amount, amount_to_withdraw)?;
+            let transfer_to_taker_ix = spl_token::instruction::transfer(
+                token_program.key,
+                pdas_temp_token_account.key,
+                takers_token_to_receive_account.key,
+                &pda,
+                &[&pda],
+                amount_to_withdraw,
+            )?;
+            msg!("Calling the token program to transfer {} tokens to the taker...", amount_to_withdraw);
+            log_compute_units();
             invoke_signed(
                 &transfer_to_taker_ix,
                 &[
@@ -189,34 +1049,2812 @@ impl Processor {
                 ],
                 &[&[&b"escrow"[..], &[nonce]]],
             )?;
-            let close_pdas_temp_acc_ix = spl_token::instruction::close_account(
+            log_compute_units();
+            if let Some(mint_account) = mint_account {
+                let freeze_ix = spl_token::instruction::freeze_account(
+                    token_program.key,
+                    pdas_temp_token_account.key,
+                    mint_account.key,
+                    &pda,
+                    &[&pda],
+                )?;
+                msg!("Calling the token program to freeze the temp token account again...");
+                log_compute_units();
+                invoke_signed(
+                    &freeze_ix,
+                    &[
+                        pdas_temp_token_account.clone(),
+                        mint_account.clone(),
+                        pda_account.clone(),
+                        token_program.clone(),
+                    ],
+                    &[&[&b"escrow"[..], &[nonce]]],
+                )?;
+                log_compute_units();
+            }
+            // store new info into escro account
+            escrow_info.deposited_amount = remaining_amount;
+            escrow_info.total_withdrawn =
+                math::add(escrow_info.total_withdrawn, amount_to_withdraw)?;
+            escrow_info.last_withdraw_unix_timestamp = now;
+            escrow_info.withdraw_nonce = math::add(escrow_info.withdraw_nonce, 1)?;
+            let status = escrow_info.status(now);
+            msg!("Escrow status: {:?}", status);
+            set_withdraw_return_data(status, &escrow_info);
+            Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+            return Ok(ProcessOutcome::Withdrawn {
+                remaining: remaining_amount,
+            });
+        }
+        else{
+            // only the escrowed amount goes to the taker, anything else sitting in the temp
+            // account was never part of the trade
+            let amount_to_transfer = escrow_info.deposited_amount;
+            let transfer_to_taker_ix = spl_token::instruction::transfer(
                 token_program.key,
                 pdas_temp_token_account.key,
-                initializers_main_account.key,
+                takers_token_to_receive_account.key,
                 &pda,
                 &[&pda],
+                amount_to_transfer,
             )?;
-            msg!("Calling the token program to close pda's temp account...");
+            msg!("Calling the token program to transfer all tokens to the taker...");
+            log_compute_units();
             invoke_signed(
-                &close_pdas_temp_acc_ix,
+                &transfer_to_taker_ix,
                 &[
                     pdas_temp_token_account.clone(),
-                    initializers_main_account.clone(),
+                    takers_token_to_receive_account.clone(),
                     pda_account.clone(),
                     token_program.clone(),
                 ],
                 &[&[&b"escrow"[..], &[nonce]]],
             )?;
+            log_compute_units();
+            if escrow_info.freeze_mode {
+                // the temp account still belongs to the initializer, nothing to close
+            } else if pdas_temp_token_account_info.amount > amount_to_transfer {
+                let owner_change_ix = spl_token::instruction::set_authority(
+                    token_program.key,
+                    pdas_temp_token_account.key,
+                    Some(initializers_main_account.key),
+                    spl_token::instruction::AuthorityType::AccountOwner,
+                    &pda,
+                    &[&pda],
+                )?;
+                msg!("Calling the token program to hand the surplus back to the initializer...");
+                log_compute_units();
+                invoke_signed(
+                    &owner_change_ix,
+                    &[
+                        pdas_temp_token_account.clone(),
+                        pda_account.clone(),
+                        token_program.clone(),
+                    ],
+                    &[&[&b"escrow"[..], &[nonce]]],
+                )?;
+                log_compute_units();
+            } else {
+                let close_pdas_temp_acc_ix = spl_token::instruction::close_account(
+                    token_program.key,
+                    pdas_temp_token_account.key,
+                    initializers_main_account.key,
+                    &pda,
+                    &[&pda],
+                )?;
+                msg!("Calling the token program to close pda's temp account...");
+                log_compute_units();
+                invoke_signed(
+                    &close_pdas_temp_acc_ix,
+                    &[
+                        pdas_temp_token_account.clone(),
+                        initializers_main_account.clone(),
+                        pda_account.clone(),
+                        token_program.clone(),
+                    ],
+                    &[&[&b"escrow"[..], &[nonce]]],
+                )?;
+                log_compute_units();
+            }
+
+            #[cfg(feature = "escrow-limit")]
+            decrement_escrow_count(
+                initializers_main_account.key,
+                next_account_info(account_info_iter)?,
+                program_id,
+            )?;
 
+            // initializers_main_account was matched against escrow_info.initializer_pubkey above,
+            // so the rent can only go back to the initializer or the treasury, never to the taker
+            let rent_recipient = treasury_account(&escrow_info, account_info_iter, program_id)?
+                .unwrap_or(initializers_main_account);
             msg!("Closing the escrow account...");
-            **initializers_main_account.try_borrow_mut_lamports()? = initializers_main_account
-                .lamports()
-                .checked_add(escrow_account.lamports())
-                .ok_or(EscrowError::AmountOverflow)?;
-            **escrow_account.try_borrow_mut_lamports()? = 0;
-            *escrow_account.try_borrow_mut_data()? = &mut [];
+            credit_lamports(escrow_account, rent_recipient, &Rent::get()?)?;
+            escrow_account.try_borrow_mut_data()?.fill(0);
+
+            if let Some(registry_account) =
+                registry_entry(&escrow_info, escrow_account.key, account_info_iter, program_id)?
+            {
+                msg!("Closing the registry entry...");
+                credit_lamports(registry_account, initializers_main_account, &Rent::get()?)?;
+                registry_account.try_borrow_mut_data()?.fill(0);
+            }
+
+            set_withdraw_return_data(EscrowStatus::Closed, &escrow_info);
         }
 
-        Ok(())
+        Ok(ProcessOutcome::Closed)
+    }
+
+    fn process_init_lamport_escrow(
+        accounts: &[AccountInfo],
+        params: InitEscrowParams,
+        program_id: &Pubkey,
+    ) -> Result<ProcessOutcome, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let withdrawer_account = next_account_info(account_info_iter)?;
+
+        if withdrawer_account.is_signer || withdrawer_account.is_writable {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        if params.require_wallet_withdrawer && !withdrawer_account.key.is_on_curve() {
+            return Err(EscrowError::WithdrawerMustBeWallet.into());
+        }
+
+        let escrow_account = next_account_info(account_info_iter)?;
+        let rent = &Rent::get()?;
+
+        assert_owned_by(escrow_account, program_id)?;
+
+        if rent.minimum_balance(escrow_account.data_len()) == 0
+            || !rent.is_exempt(escrow_account.lamports(), escrow_account.data_len())
+        {
+            return Err(EscrowError::NotRentExempt.into());
+        }
+
+        if params.require_exact_rent
+            && escrow_account.lamports() > rent.minimum_balance(escrow_account.data_len())
+        {
+            return Err(EscrowError::UnexpectedLamports.into());
+        }
+
+        let mut escrow_info = Escrow::unpack_unchecked(&escrow_account.try_borrow_data()?)?;
+        if escrow_info.is_initialized() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        if escrow_account.data_len() != Escrow::LEN
+            || escrow_account.try_borrow_data()?.iter().any(|&byte| byte != 0)
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        assert_valid_milestones(&params)?;
+
+        escrow_info.is_initialized = true;
+        escrow_info.initializer_pubkey = *initializer.key;
+        escrow_info.withdrawer_pubkey = *withdrawer_account.key;
+        escrow_info.deposited_amount = params.amount;
+        escrow_info.reserve_amount = params.reserve_amount;
+        escrow_info.milestone_count = params.milestone_count;
+        escrow_info.milestones = params.milestones;
+        escrow_info.lamport_mode = true;
+        escrow_info.full_only = params.full_only;
+        escrow_info.authority_pubkey = params.authority;
+        escrow_info.withdraw_cooldown_secs = params.withdraw_cooldown_secs;
+        escrow_info.hashlock = params.hashlock;
+        escrow_info.expiry_unix_timestamp = params.expiry_unix_timestamp;
+        escrow_info.reference_id = params.reference_id;
+
+        let system_program = next_account_info(account_info_iter)?;
+
+        let transfer_ix =
+            system_instruction::transfer(initializer.key, escrow_account.key, params.amount);
+        msg!("Calling the system program to transfer {} lamports into the escrow...", params.amount);
+        log_compute_units();
+        invoke(
+            &transfer_ix,
+            &[
+                initializer.clone(),
+                escrow_account.clone(),
+                system_program.clone(),
+            ],
+        )?;
+        log_compute_units();
+
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        #[cfg(feature = "escrow-limit")]
+        increment_escrow_count(
+            initializer,
+            next_account_info(account_info_iter)?,
+            system_program,
+            rent,
+            program_id,
+        )?;
+
+        log_escrow_created(
+            escrow_account.key,
+            &spl_token::native_mint::id(),
+            withdrawer_account.key,
+            params.amount,
+        );
+        set_return_data(
+            &[escrow_account.key.as_ref(), &params.reference_id.to_le_bytes()].concat(),
+        );
+        Ok(ProcessOutcome::Initialized {
+            escrow: *escrow_account.key,
+        })
+    }
+
+    fn process_withdraw_lamports(
+        accounts: &[AccountInfo],
+        amount_to_withdraw: u64,
+        preimage: Option<[u8; 32]>,
+        program_id: &Pubkey,
+    ) -> Result<ProcessOutcome, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let taker = next_account_info(account_info_iter)?;
+
+        if !taker.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let escrow_account = next_account_info(account_info_iter)?;
+        let initializers_main_account = next_account_info(account_info_iter)?;
+
+        if taker.key == escrow_account.key || initializers_main_account.key == escrow_account.key {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        assert_not_closed(escrow_account)?;
+        assert_owned_by(escrow_account, program_id)?;
+
+        let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if !escrow_info.lamport_mode {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if escrow_info.initializer_pubkey != *initializers_main_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if escrow_info.withdrawer_pubkey != *taker.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let now = assert_withdrawable(
+            &escrow_info,
+            escrow_account.key,
+            taker.key,
+            amount_to_withdraw,
+            accounts,
+        )?;
+        assert_preimage(&escrow_info, preimage.as_ref())?;
+
+        // the program owns the escrow account, so its lamports can be moved without a CPI
+        **escrow_account.try_borrow_mut_lamports()? =
+            math::sub(escrow_account.lamports(), amount_to_withdraw)?;
+        **taker.try_borrow_mut_lamports()? = math::add(taker.lamports(), amount_to_withdraw)?;
+
+        if amount_to_withdraw < escrow_info.deposited_amount {
+            escrow_info.deposited_amount =
+                math::sub(escrow_info.deposited_amount, amount_to_withdraw)?;
+            escrow_info.total_withdrawn =
+                math::add(escrow_info.total_withdrawn, amount_to_withdraw)?;
+            escrow_info.last_withdraw_unix_timestamp = now;
+            escrow_info.withdraw_nonce = math::add(escrow_info.withdraw_nonce, 1)?;
+            let status = escrow_info.status(now);
+            msg!("Escrow status: {:?}", status);
+            let remaining = escrow_info.deposited_amount;
+            set_withdraw_return_data(status, &escrow_info);
+            Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+            return Ok(ProcessOutcome::Withdrawn { remaining });
+        } else {
+            msg!("Closing the escrow account...");
+            credit_lamports(escrow_account, initializers_main_account, &Rent::get()?)?;
+            escrow_account.try_borrow_mut_data()?.fill(0);
+
+            #[cfg(feature = "escrow-limit")]
+            decrement_escrow_count(
+                initializers_main_account.key,
+                next_account_info(account_info_iter)?,
+                program_id,
+            )?;
+
+            set_withdraw_return_data(EscrowStatus::Closed, &escrow_info);
+        }
+
+        Ok(ProcessOutcome::Closed)
+    }
+
+    fn process_split_escrow(
+        accounts: &[AccountInfo],
+        amount: u64,
+        program_id: &Pubkey,
+    ) -> Result<ProcessOutcome, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let escrow_account = next_account_info(account_info_iter)?;
+        assert_owned_by(escrow_account, program_id)?;
+        let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.initializer_pubkey != *initializer.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if escrow_info.freeze_mode || escrow_info.lamport_mode {
+            return Err(EscrowError::InvalidInstruction.into());
+        }
+
+        if escrow_info.sealed {
+            return Err(EscrowError::EscrowSealed.into());
+        }
+
+        // splitting off to a withdrawer of their choosing would be a cancel in disguise
+        if escrow_info.expiry_unix_timestamp != 0
+            && !escrow_info.is_expired(current_clock(accounts)?.unix_timestamp)
+        {
+            return Err(EscrowError::EscrowNotExpired.into());
+        }
+
+        let pdas_temp_token_account = next_account_info(account_info_iter)?;
+        if escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if amount >= escrow_info.deposited_amount {
+            return Err(EscrowError::ExpectedAmountMismatch.into());
+        }
+        let remaining = math::sub(escrow_info.deposited_amount, amount)?;
+        if remaining < escrow_info.reserve_amount {
+            return Err(EscrowError::BelowReserve.into());
+        }
+        // the new escrow takes its share of the withdrawn amount so that the milestones unlock
+        // the same fraction of both deposits
+        let withdrawn_share =
+            math::mul_div(escrow_info.total_withdrawn, amount, escrow_info.deposited_amount)?;
+
+        let new_temp_token_account = next_account_info(account_info_iter)?;
+        let new_withdrawer_account = next_account_info(account_info_iter)?;
+        if new_withdrawer_account.is_signer || new_withdrawer_account.is_writable {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let new_escrow_account = next_account_info(account_info_iter)?;
+        let rent = &Rent::get()?;
+
+        if rent.minimum_balance(new_escrow_account.data_len()) == 0
+            || !rent.is_exempt(new_escrow_account.lamports(), new_escrow_account.data_len())
+        {
+            return Err(EscrowError::NotRentExempt.into());
+        }
+
+        assert_owned_by(new_escrow_account, program_id)?;
+        let mut new_escrow_info = Escrow::unpack_unchecked(&new_escrow_account.try_borrow_data()?)?;
+        if new_escrow_info.is_initialized() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        if new_escrow_account.data_len() != Escrow::LEN
+            || new_escrow_account.try_borrow_data()?.iter().any(|&byte| byte != 0)
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let token_program = next_account_info(account_info_iter)?;
+        if *token_program.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let pda_account = next_account_info(account_info_iter)?;
+        let (pda, nonce) = Pubkey::find_program_address(&[b"escrow"], program_id);
+
+        let transfer_to_new_temp_ix = spl_token::instruction::transfer(
+            token_program.key,
+            pdas_temp_token_account.key,
+            new_temp_token_account.key,
+            &pda,
+            &[&pda],
+            amount,
+        )?;
+        msg!("Calling the token program to move {} tokens into the new temp account...", amount);
+        log_compute_units();
+        invoke_signed(
+            &transfer_to_new_temp_ix,
+            &[
+                pdas_temp_token_account.clone(),
+                new_temp_token_account.clone(),
+                pda_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[&b"escrow"[..], &[nonce]]],
+        )?;
+        log_compute_units();
+
+        let owner_change_ix = spl_token::instruction::set_authority(
+            token_program.key,
+            new_temp_token_account.key,
+            Some(&pda),
+            spl_token::instruction::AuthorityType::AccountOwner,
+            initializer.key,
+            &[initializer.key],
+        )?;
+        msg!("Calling the token program to transfer new temp account ownership...");
+        log_compute_units();
+        invoke(
+            &owner_change_ix,
+            &[
+                new_temp_token_account.clone(),
+                initializer.clone(),
+                token_program.clone(),
+            ],
+        )?;
+        log_compute_units();
+
+        new_escrow_info.is_initialized = true;
+        new_escrow_info.initializer_pubkey = *initializer.key;
+        new_escrow_info.temp_token_account_pubkey = *new_temp_token_account.key;
+        new_escrow_info.withdrawer_pubkey = *new_withdrawer_account.key;
+        new_escrow_info.deposited_amount = amount;
+        new_escrow_info.total_withdrawn = withdrawn_share;
+        new_escrow_info.milestone_count = escrow_info.milestone_count;
+        new_escrow_info.milestones = escrow_info.milestones;
+        new_escrow_info.full_only = escrow_info.full_only;
+        new_escrow_info.authority_pubkey = escrow_info.authority_pubkey;
+        new_escrow_info.dust_threshold = escrow_info.dust_threshold;
+        new_escrow_info.dust_collector_pubkey = escrow_info.dust_collector_pubkey;
+        new_escrow_info.last_withdraw_unix_timestamp = escrow_info.last_withdraw_unix_timestamp;
+        new_escrow_info.withdraw_cooldown_secs = escrow_info.withdraw_cooldown_secs;
+        new_escrow_info.rent_to_treasury = escrow_info.rent_to_treasury;
+        new_escrow_info.require_receive_owner = escrow_info.require_receive_owner;
+        new_escrow_info.hashlock = escrow_info.hashlock;
+        new_escrow_info.expiry_unix_timestamp = escrow_info.expiry_unix_timestamp;
+        new_escrow_info.reference_id = escrow_info.reference_id;
+        new_escrow_info.create_receive_account = escrow_info.create_receive_account;
+        Escrow::pack(new_escrow_info, &mut new_escrow_account.try_borrow_mut_data()?)?;
+
+        escrow_info.total_withdrawn = math::sub(escrow_info.total_withdrawn, withdrawn_share)?;
+        escrow_info.deposited_amount = remaining;
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        #[cfg(feature = "escrow-limit")]
+        {
+            let counter_account = next_account_info(account_info_iter)?;
+            let system_program = next_account_info(account_info_iter)?;
+            increment_escrow_count(initializer, counter_account, system_program, rent, program_id)?;
+        }
+
+        Ok(ProcessOutcome::Initialized {
+            escrow: *new_escrow_account.key,
+        })
+    }
+
+    fn process_merge_escrow(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> Result<ProcessOutcome, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let escrow_account = next_account_info(account_info_iter)?;
+        let pdas_temp_token_account = next_account_info(account_info_iter)?;
+        let merged_escrow_account = next_account_info(account_info_iter)?;
+        let merged_pdas_temp_token_account = next_account_info(account_info_iter)?;
+
+        if escrow_account.key == merged_escrow_account.key {
+            return Err(EscrowError::IncompatibleEscrows.into());
+        }
+
+        assert_owned_by(escrow_account, program_id)?;
+        assert_not_closed(merged_escrow_account)?;
+        assert_owned_by(merged_escrow_account, program_id)?;
+
+        let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+        let merged_escrow_info = Escrow::unpack(&merged_escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.initializer_pubkey != *initializer.key
+            || merged_escrow_info.initializer_pubkey != *initializer.key
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if escrow_info.freeze_mode
+            || escrow_info.lamport_mode
+            || merged_escrow_info.freeze_mode
+            || merged_escrow_info.lamport_mode
+        {
+            return Err(EscrowError::InvalidInstruction.into());
+        }
+
+        if escrow_info.sealed || merged_escrow_info.sealed {
+            return Err(EscrowError::EscrowSealed.into());
+        }
+
+        if escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key
+            || merged_escrow_info.temp_token_account_pubkey != *merged_pdas_temp_token_account.key
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let pdas_temp_token_account_info =
+            TokenAccount::unpack(&pdas_temp_token_account.try_borrow_data()?)?;
+        let merged_pdas_temp_token_account_info =
+            TokenAccount::unpack(&merged_pdas_temp_token_account.try_borrow_data()?)?;
+
+        if escrow_info.withdrawer_pubkey != merged_escrow_info.withdrawer_pubkey
+            || escrow_info.full_only != merged_escrow_info.full_only
+            || escrow_info.reserve_amount != merged_escrow_info.reserve_amount
+            || escrow_info.milestone_count != merged_escrow_info.milestone_count
+            || escrow_info.milestones != merged_escrow_info.milestones
+            || escrow_info.authority_pubkey != merged_escrow_info.authority_pubkey
+            || escrow_info.dust_threshold != merged_escrow_info.dust_threshold
+            || escrow_info.dust_collector_pubkey != merged_escrow_info.dust_collector_pubkey
+            || escrow_info.withdraw_cooldown_secs != merged_escrow_info.withdraw_cooldown_secs
+            || escrow_info.rent_to_treasury != merged_escrow_info.rent_to_treasury
+            || escrow_info.require_receive_owner != merged_escrow_info.require_receive_owner
+            || escrow_info.create_receive_account != merged_escrow_info.create_receive_account
+            || escrow_info.hashlock != merged_escrow_info.hashlock
+            || escrow_info.expiry_unix_timestamp != merged_escrow_info.expiry_unix_timestamp
+            || pdas_temp_token_account_info.mint != merged_pdas_temp_token_account_info.mint
+        {
+            return Err(EscrowError::IncompatibleEscrows.into());
+        }
+
+        escrow_info.deposited_amount = math::add(
+            escrow_info.deposited_amount,
+            merged_escrow_info.deposited_amount,
+        )?;
+        escrow_info.total_withdrawn = math::add(
+            escrow_info.total_withdrawn,
+            merged_escrow_info.total_withdrawn,
+        )?;
+        escrow_info.last_withdraw_unix_timestamp = escrow_info
+            .last_withdraw_unix_timestamp
+            .max(merged_escrow_info.last_withdraw_unix_timestamp);
+
+        let token_program = next_account_info(account_info_iter)?;
+        if *token_program.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let pda_account = next_account_info(account_info_iter)?;
+        let (pda, nonce) = Pubkey::find_program_address(&[b"escrow"], program_id);
+
+        let transfer_to_temp_ix = spl_token::instruction::transfer(
+            token_program.key,
+            merged_pdas_temp_token_account.key,
+            pdas_temp_token_account.key,
+            &pda,
+            &[&pda],
+            merged_escrow_info.deposited_amount,
+        )?;
+        msg!("Calling the token program to move the merged escrow's tokens...");
+        log_compute_units();
+        invoke_signed(
+            &transfer_to_temp_ix,
+            &[
+                merged_pdas_temp_token_account.clone(),
+                pdas_temp_token_account.clone(),
+                pda_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[&b"escrow"[..], &[nonce]]],
+        )?;
+        log_compute_units();
+
+        // tokens sent to the temp account on top of the deposit were never escrowed
+        if merged_pdas_temp_token_account_info.amount > merged_escrow_info.deposited_amount {
+            let owner_change_ix = spl_token::instruction::set_authority(
+                token_program.key,
+                merged_pdas_temp_token_account.key,
+                Some(initializer.key),
+                spl_token::instruction::AuthorityType::AccountOwner,
+                &pda,
+                &[&pda],
+            )?;
+            msg!("Calling the token program to hand the surplus back to the initializer...");
+            log_compute_units();
+            invoke_signed(
+                &owner_change_ix,
+                &[
+                    merged_pdas_temp_token_account.clone(),
+                    pda_account.clone(),
+                    token_program.clone(),
+                ],
+                &[&[&b"escrow"[..], &[nonce]]],
+            )?;
+            log_compute_units();
+        } else {
+            let close_pdas_temp_acc_ix = spl_token::instruction::close_account(
+                token_program.key,
+                merged_pdas_temp_token_account.key,
+                initializer.key,
+                &pda,
+                &[&pda],
+            )?;
+            msg!("Calling the token program to close the merged escrow's temp account...");
+            log_compute_units();
+            invoke_signed(
+                &close_pdas_temp_acc_ix,
+                &[
+                    merged_pdas_temp_token_account.clone(),
+                    initializer.clone(),
+                    pda_account.clone(),
+                    token_program.clone(),
+                ],
+                &[&[&b"escrow"[..], &[nonce]]],
+            )?;
+            log_compute_units();
+        }
+
+        #[cfg(feature = "escrow-limit")]
+        decrement_escrow_count(initializer.key, next_account_info(account_info_iter)?, program_id)?;
+
+        let rent_recipient = treasury_account(&merged_escrow_info, account_info_iter, program_id)?
+            .unwrap_or(initializer);
+
+        // both escrows share the (initializer, mint, withdrawer) of the entry, it stays taken by
+        // the escrow merged into
+        if let Some(registry_account) = registry_entry(
+            &merged_escrow_info,
+            merged_escrow_account.key,
+            account_info_iter,
+            program_id,
+        )? {
+            msg!("Moving the registry entry to the escrow merged into...");
+            registry_account
+                .try_borrow_mut_data()?
+                .copy_from_slice(escrow_account.key.as_ref());
+            escrow_info.registered = true;
+        }
+
+        let remaining = escrow_info.deposited_amount;
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        msg!("Closing the merged escrow account...");
+        credit_lamports(merged_escrow_account, rent_recipient, &Rent::get()?)?;
+        merged_escrow_account.try_borrow_mut_data()?.fill(0);
+
+        Ok(ProcessOutcome::Updated { remaining })
+    }
+
+    fn process_drain(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> Result<ProcessOutcome, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let escrow_account = next_account_info(account_info_iter)?;
+        assert_owned_by(escrow_account, program_id)?;
+        let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.initializer_pubkey != *initializer.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if escrow_info.freeze_mode || escrow_info.lamport_mode {
+            return Err(EscrowError::InvalidInstruction.into());
+        }
+
+        if escrow_info.sealed {
+            return Err(EscrowError::EscrowSealed.into());
+        }
+
+        // as for a cancel, an HTLC's taker can count on the deposit until the expiry
+        if escrow_info.expiry_unix_timestamp != 0
+            && !escrow_info.is_expired(current_clock(accounts)?.unix_timestamp)
+        {
+            return Err(EscrowError::EscrowNotExpired.into());
+        }
+
+        let pdas_temp_token_account = next_account_info(account_info_iter)?;
+        if escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let initializers_token_account = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        if *token_program.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let pda_account = next_account_info(account_info_iter)?;
+        let (pda, nonce) = Pubkey::find_program_address(&[b"escrow"], program_id);
+
+        // only what this escrow holds, anything else in the temp account was never deposited
+        let amount = escrow_info.deposited_amount;
+        let transfer_to_initializer_ix = spl_token::instruction::transfer(
+            token_program.key,
+            pdas_temp_token_account.key,
+            initializers_token_account.key,
+            &pda,
+            &[&pda],
+            amount,
+        )?;
+        msg!("Calling the token program to return {} tokens to the initializer...", amount);
+        log_compute_units();
+        invoke_signed(
+            &transfer_to_initializer_ix,
+            &[
+                pdas_temp_token_account.clone(),
+                initializers_token_account.clone(),
+                pda_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[&b"escrow"[..], &[nonce]]],
+        )?;
+        log_compute_units();
+
+        escrow_info.deposited_amount = 0;
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        Ok(ProcessOutcome::Updated { remaining: 0 })
+    }
+
+    fn process_cancel_partial(
+        accounts: &[AccountInfo],
+        amount: u64,
+        program_id: &Pubkey,
+    ) -> Result<ProcessOutcome, ProgramError> {
+        if amount == 0 {
+            return Err(EscrowError::ZeroAmount.into());
+        }
+
+        let account_info_iter = &mut accounts.iter();
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let escrow_account = next_account_info(account_info_iter)?;
+
+        assert_not_closed(escrow_account)?;
+        assert_owned_by(escrow_account, program_id)?;
+
+        let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.initializer_pubkey != *initializer.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if escrow_info.freeze_mode || escrow_info.lamport_mode {
+            return Err(EscrowError::InvalidInstruction.into());
+        }
+
+        if escrow_info.sealed {
+            return Err(EscrowError::EscrowSealed.into());
+        }
+
+        if escrow_info.expiry_unix_timestamp != 0
+            && !escrow_info.is_expired(current_clock(accounts)?.unix_timestamp)
+        {
+            return Err(EscrowError::EscrowNotExpired.into());
+        }
+
+        // returning everything is a full cancel, which also closes the accounts
+        if amount >= escrow_info.deposited_amount {
+            return Err(EscrowError::ExpectedAmountMismatch.into());
+        }
+        let remaining = math::sub(escrow_info.deposited_amount, amount)?;
+        if remaining < escrow_info.reserve_amount {
+            return Err(EscrowError::BelowReserve.into());
+        }
+
+        let pdas_temp_token_account = next_account_info(account_info_iter)?;
+        if escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let pdas_temp_token_account_info =
+            TokenAccount::unpack(&pdas_temp_token_account.try_borrow_data()?)?;
+
+        let initializers_token_account = next_account_info(account_info_iter)?;
+        assert_usable_token_account(
+            initializers_token_account,
+            Some(&pdas_temp_token_account_info.mint),
+            None,
+        )?;
+
+        let token_program = next_account_info(account_info_iter)?;
+        if *token_program.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let pda_account = next_account_info(account_info_iter)?;
+        let (pda, nonce) = Pubkey::find_program_address(&[b"escrow"], program_id);
+
+        let transfer_to_initializer_ix = spl_token::instruction::transfer(
+            token_program.key,
+            pdas_temp_token_account.key,
+            initializers_token_account.key,
+            &pda,
+            &[&pda],
+            amount,
+        )?;
+        msg!("Calling the token program to return {} tokens to the initializer...", amount);
+        log_compute_units();
+        invoke_signed(
+            &transfer_to_initializer_ix,
+            &[
+                pdas_temp_token_account.clone(),
+                initializers_token_account.clone(),
+                pda_account.clone(),
+                token_program.clone(),
+            ],
+            &[&[&b"escrow"[..], &[nonce]]],
+        )?;
+        log_compute_units();
+
+        escrow_info.deposited_amount = remaining;
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        Ok(ProcessOutcome::Updated { remaining })
+    }
+
+    fn process_decline(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> Result<ProcessOutcome, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let withdrawer = next_account_info(account_info_iter)?;
+
+        if !withdrawer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let escrow_account = next_account_info(account_info_iter)?;
+
+        assert_not_closed(escrow_account)?;
+        assert_owned_by(escrow_account, program_id)?;
+
+        let escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.withdrawer_pubkey != *withdrawer.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if escrow_info.lamport_mode {
+            return Err(EscrowError::InvalidInstruction.into());
+        }
+
+        let pdas_temp_token_account = next_account_info(account_info_iter)?;
+        if escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let initializers_token_account = next_account_info(account_info_iter)?;
+        let initializers_main_account = next_account_info(account_info_iter)?;
+        if escrow_info.initializer_pubkey != *initializers_main_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        // the withdrawer picks the accounts here, the tokens must provably go to the initializer
+        if !escrow_info.freeze_mode {
+            let mint = TokenAccount::unpack(&pdas_temp_token_account.try_borrow_data()?)?.mint;
+            assert_usable_token_account(
+                initializers_token_account,
+                Some(&mint),
+                Some(&escrow_info.initializer_pubkey),
+            )?;
+        }
+
+        Self::release_to_initializer(
+            &escrow_info,
+            escrow_account,
+            pdas_temp_token_account,
+            initializers_token_account,
+            initializers_main_account,
+            account_info_iter,
+            program_id,
+        )?;
+
+        Ok(ProcessOutcome::Closed)
+    }
+
+    fn process_cancel_escrow(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> Result<ProcessOutcome, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let escrow_account = next_account_info(account_info_iter)?;
+
+        assert_not_closed(escrow_account)?;
+        assert_owned_by(escrow_account, program_id)?;
+
+        let escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.initializer_pubkey != *initializer.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        // until the expiry the withdrawer can count on the deposit staying put, after it even
+        // a sealed escrow goes back to its initializer
+        let expired = escrow_info.expiry_unix_timestamp != 0
+            && escrow_info.is_expired(current_clock(accounts)?.unix_timestamp);
+        if escrow_info.sealed && !expired {
+            return Err(EscrowError::EscrowSealed.into());
+        }
+        if escrow_info.expiry_unix_timestamp != 0 && !expired {
+            return Err(EscrowError::EscrowNotExpired.into());
+        }
+
+        if escrow_info.lamport_mode {
+            // the remaining lamports sit in the escrow account next to its rent
+            msg!("Closing the escrow account...");
+            credit_lamports(escrow_account, initializer, &Rent::get()?)?;
+            escrow_account.try_borrow_mut_data()?.fill(0);
+
+            #[cfg(feature = "escrow-limit")]
+            decrement_escrow_count(initializer.key, next_account_info(account_info_iter)?, program_id)?;
+
+            return Ok(ProcessOutcome::Closed);
+        }
+
+        let pdas_temp_token_account = next_account_info(account_info_iter)?;
+        if escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let initializers_token_account = next_account_info(account_info_iter)?;
+
+        Self::release_to_initializer(
+            &escrow_info,
+            escrow_account,
+            pdas_temp_token_account,
+            initializers_token_account,
+            initializer,
+            account_info_iter,
+            program_id,
+        )?;
+
+        Ok(ProcessOutcome::Closed)
+    }
+
+    fn process_withdraw_treasury(
+        accounts: &[AccountInfo],
+        amount: u64,
+        program_id: &Pubkey,
+    ) -> Result<ProcessOutcome, ProgramError> {
+        if amount == 0 {
+            return Err(EscrowError::ZeroAmount.into());
+        }
+
+        let account_info_iter = &mut accounts.iter();
+        let upgrade_authority = next_account_info(account_info_iter)?;
+        let treasury = next_account_info(account_info_iter)?;
+        let recipient = next_account_info(account_info_iter)?;
+        let program_data = next_account_info(account_info_iter)?;
+
+        assert_upgrade_authority(upgrade_authority, program_data, program_id)?;
+
+        let (treasury_pda, treasury_nonce) = Pubkey::find_program_address(&[b"treasury"], program_id);
+        if *treasury.key != treasury_pda {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let system_program = next_account_info(account_info_iter)?;
+
+        let transfer_ix = system_instruction::transfer(treasury.key, recipient.key, amount);
+        msg!("Calling the system program to withdraw {} lamports from the treasury...", amount);
+        log_compute_units();
+        invoke_signed(
+            &transfer_ix,
+            &[treasury.clone(), recipient.clone(), system_program.clone()],
+            &[&[&b"treasury"[..], &[treasury_nonce]]],
+        )?;
+        log_compute_units();
+
+        Ok(ProcessOutcome::Unchanged)
+    }
+
+    fn process_assert_state(
+        accounts: &[AccountInfo],
+        expected_withdrawer: &Pubkey,
+        min_remaining: u64,
+        program_id: &Pubkey,
+    ) -> Result<ProcessOutcome, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let escrow_account = next_account_info(account_info_iter)?;
+
+        assert_owned_by(escrow_account, program_id)?;
+
+        let escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.withdrawer_pubkey != *expected_withdrawer
+            || escrow_info.deposited_amount < min_remaining
+        {
+            return Err(EscrowError::StateAssertionFailed.into());
+        }
+
+        Ok(ProcessOutcome::Unchanged)
+    }
+
+    fn process_state_hash(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> Result<ProcessOutcome, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let escrow_account = next_account_info(account_info_iter)?;
+
+        assert_owned_by(escrow_account, program_id)?;
+
+        let escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+        set_return_data(escrow_info.state_hash().as_ref());
+
+        Ok(ProcessOutcome::Unchanged)
+    }
+
+    fn process_seal(
+        accounts: &[AccountInfo],
+        program_id: &Pubkey,
+    ) -> Result<ProcessOutcome, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let escrow_account = next_account_info(account_info_iter)?;
+
+        assert_not_closed(escrow_account)?;
+        assert_owned_by(escrow_account, program_id)?;
+
+        let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.initializer_pubkey != *initializer.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if escrow_info.sealed {
+            return Err(EscrowError::EscrowSealed.into());
+        }
+
+        escrow_info.sealed = true;
+        let remaining = escrow_info.deposited_amount;
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        Ok(ProcessOutcome::Updated { remaining })
+    }
+
+    fn process_withdraw_split(
+        accounts: &[AccountInfo],
+        allocations: &[(u8, u64)],
+        program_id: &Pubkey,
+    ) -> Result<ProcessOutcome, ProgramError> {
+        if allocations.is_empty() {
+            return Err(EscrowError::InvalidInstruction.into());
+        }
+        if allocations.iter().any(|&(_, amount)| amount == 0) {
+            return Err(EscrowError::ZeroAmount.into());
+        }
+        let amount_to_withdraw = allocations
+            .iter()
+            .try_fold(0, |total, &(_, amount)| math::add(total, amount))?;
+
+        let account_info_iter = &mut accounts.iter();
+        let taker = next_account_info(account_info_iter)?;
+
+        if !taker.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let escrow_account = next_account_info(account_info_iter)?;
+        let (pda, nonce) = Pubkey::find_program_address(&[b"escrow"], program_id);
+
+        assert_owned_by(escrow_account, program_id)?;
+        if *escrow_account.key == pda {
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.freeze_mode || escrow_info.lamport_mode {
+            return Err(EscrowError::InvalidInstruction.into());
+        }
+
+        if escrow_info.withdrawer_pubkey != *taker.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let pdas_temp_token_account = next_account_info(account_info_iter)?;
+        if escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        assert_usable_token_account(pdas_temp_token_account, None, Some(&pda))?;
+        let mint = TokenAccount::unpack(&pdas_temp_token_account.try_borrow_data()?)?.mint;
+
+        let token_program = next_account_info(account_info_iter)?;
+        if *token_program.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let pda_account = next_account_info(account_info_iter)?;
+
+        // emptying the escrow would need the close logic of a regular withdraw
+        if amount_to_withdraw >= escrow_info.deposited_amount {
+            return Err(EscrowError::InvalidInstruction.into());
+        }
+
+        let now = assert_withdrawable(
+            &escrow_info,
+            escrow_account.key,
+            taker.key,
+            amount_to_withdraw,
+            accounts,
+        )?;
+        assert_preimage(&escrow_info, None)?;
+
+        let receive_accounts = account_info_iter.as_slice();
+        for &(index, amount) in allocations {
+            let receive_account = receive_accounts
+                .get(index as usize)
+                .ok_or(ProgramError::NotEnoughAccountKeys)?;
+            assert_usable_token_account(receive_account, Some(&mint), None)?;
+            assert_receive_owner(&escrow_info, receive_account, taker.key)?;
+            if receive_account.key == pdas_temp_token_account.key
+                || receive_account.key == escrow_account.key
+                || TokenAccount::unpack(&receive_account.try_borrow_data()?)?.owner == pda
+            {
+                return Err(ProgramError::InvalidAccountData);
+            }
+
+            let transfer_to_taker_ix = spl_token::instruction::transfer(
+                token_program.key,
+                pdas_temp_token_account.key,
+                receive_account.key,
+                &pda,
+                &[&pda],
+                amount,
+            )?;
+            msg!("Calling the token program to transfer {} tokens to the taker...", amount);
+            log_compute_units();
+            invoke_signed(
+                &transfer_to_taker_ix,
+                &[
+                    pdas_temp_token_account.clone(),
+                    receive_account.clone(),
+                    pda_account.clone(),
+                    token_program.clone(),
+                ],
+                &[&[&b"escrow"[..], &[nonce]]],
+            )?;
+            log_compute_units();
+        }
+
+        let remaining = math::sub(escrow_info.deposited_amount, amount_to_withdraw)?;
+        escrow_info.deposited_amount = remaining;
+        escrow_info.total_withdrawn = math::add(escrow_info.total_withdrawn, amount_to_withdraw)?;
+        escrow_info.last_withdraw_unix_timestamp = now;
+        escrow_info.withdraw_nonce = math::add(escrow_info.withdraw_nonce, 1)?;
+        let status = escrow_info.status(now);
+        msg!("Escrow status: {:?}", status);
+        set_withdraw_return_data(status, &escrow_info);
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        Ok(ProcessOutcome::Withdrawn { remaining })
+    }
+
+    /// Hands the remaining deposit back to the initializer and closes the escrow. The temp account
+    /// is closed too, or handed to the initializer if it holds more than the deposit. A `freeze`
+    /// escrow's temp account is thawed, and its PDA delegate approval revoked when the initializer
+    /// signed the transaction. Expects the token program, the PDA account, the mint for `freeze`
+    /// escrows or the dust collector for escrows with a dust threshold, and the counter for
+    /// `escrow-limit` next in `account_info_iter`.
+    fn release_to_initializer<'a, 'b>(
+        escrow_info: &Escrow,
+        escrow_account: &AccountInfo<'a>,
+        pdas_temp_token_account: &AccountInfo<'a>,
+        initializers_token_account: &AccountInfo<'a>,
+        initializers_main_account: &AccountInfo<'a>,
+        account_info_iter: &mut std::slice::Iter<'b, AccountInfo<'a>>,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let token_program = next_account_info(account_info_iter)?;
+        if *token_program.key != spl_token::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let pda_account = next_account_info(account_info_iter)?;
+        let (pda, nonce) = Pubkey::find_program_address(&[b"escrow"], program_id);
+
+        if escrow_info.freeze_mode {
+            // the tokens never left the initializer's account, thawing it is enough
+            let mint_account = next_account_info(account_info_iter)?;
+            let thaw_ix = spl_token::instruction::thaw_account(
+                token_program.key,
+                pdas_temp_token_account.key,
+                mint_account.key,
+                &pda,
+                &[&pda],
+            )?;
+            msg!("Calling the token program to thaw the temp token account...");
+            log_compute_units();
+            invoke_signed(
+                &thaw_ix,
+                &[
+                    pdas_temp_token_account.clone(),
+                    mint_account.clone(),
+                    pda_account.clone(),
+                    token_program.clone(),
+                ],
+                &[&[&b"escrow"[..], &[nonce]]],
+            )?;
+            log_compute_units();
+
+            // only the account owner can revoke, which the initializer is not to a Decline
+            if initializers_main_account.is_signer {
+                let revoke_ix = spl_token::instruction::revoke(
+                    token_program.key,
+                    pdas_temp_token_account.key,
+                    initializers_main_account.key,
+                    &[],
+                )?;
+                msg!("Calling the token program to revoke the PDA's delegate approval...");
+                log_compute_units();
+                invoke(
+                    &revoke_ix,
+                    &[
+                        pdas_temp_token_account.clone(),
+                        initializers_main_account.clone(),
+                        token_program.clone(),
+                    ],
+                )?;
+                log_compute_units();
+            }
+        } else {
+            let pdas_temp_token_account_info =
+                TokenAccount::unpack(&pdas_temp_token_account.try_borrow_data()?)?;
+
+            let mut destination = initializers_token_account;
+            if escrow_info.dust_threshold > 0 {
+                let dust_collector = next_account_info(account_info_iter)?;
+                // a Decline is signed by the taker, so the collector has to be the one stored at
+                // init rather than whichever account was passed
+                if escrow_info.dust_collector_pubkey != Pubkey::default() {
+                    if *dust_collector.key != escrow_info.dust_collector_pubkey {
+                        return Err(ProgramError::InvalidAccountData);
+                    }
+                    assert_usable_token_account(
+                        dust_collector,
+                        Some(&pdas_temp_token_account_info.mint),
+                        None,
+                    )?;
+                    if escrow_info.deposited_amount < escrow_info.dust_threshold {
+                        msg!("Sweeping the remaining dust to the dust collector...");
+                        destination = dust_collector;
+                    }
+                }
+            }
+
+            // only what this escrow holds, anything else in the temp account was never deposited
+            let amount = escrow_info.deposited_amount;
+            let transfer_to_initializer_ix = spl_token::instruction::transfer(
+                token_program.key,
+                pdas_temp_token_account.key,
+                destination.key,
+                &pda,
+                &[&pda],
+                amount,
+            )?;
+            msg!("Calling the token program to return the remaining {} tokens...", amount);
+            log_compute_units();
+            invoke_signed(
+                &transfer_to_initializer_ix,
+                &[
+                    pdas_temp_token_account.clone(),
+                    destination.clone(),
+                    pda_account.clone(),
+                    token_program.clone(),
+                ],
+                &[&[&b"escrow"[..], &[nonce]]],
+            )?;
+            log_compute_units();
+
+            if pdas_temp_token_account_info.amount > amount {
+                let owner_change_ix = spl_token::instruction::set_authority(
+                    token_program.key,
+                    pdas_temp_token_account.key,
+                    Some(initializers_main_account.key),
+                    spl_token::instruction::AuthorityType::AccountOwner,
+                    &pda,
+                    &[&pda],
+                )?;
+                msg!("Calling the token program to hand the surplus back to the initializer...");
+                log_compute_units();
+                invoke_signed(
+                    &owner_change_ix,
+                    &[
+                        pdas_temp_token_account.clone(),
+                        pda_account.clone(),
+                        token_program.clone(),
+                    ],
+                    &[&[&b"escrow"[..], &[nonce]]],
+                )?;
+                log_compute_units();
+            } else {
+                let close_pdas_temp_acc_ix = spl_token::instruction::close_account(
+                    token_program.key,
+                    pdas_temp_token_account.key,
+                    initializers_main_account.key,
+                    &pda,
+                    &[&pda],
+                )?;
+                msg!("Calling the token program to close pda's temp account...");
+                log_compute_units();
+                invoke_signed(
+                    &close_pdas_temp_acc_ix,
+                    &[
+                        pdas_temp_token_account.clone(),
+                        initializers_main_account.clone(),
+                        pda_account.clone(),
+                        token_program.clone(),
+                    ],
+                    &[&[&b"escrow"[..], &[nonce]]],
+                )?;
+                log_compute_units();
+            }
+        }
+
+        #[cfg(feature = "escrow-limit")]
+        decrement_escrow_count(
+            initializers_main_account.key,
+            next_account_info(account_info_iter)?,
+            program_id,
+        )?;
+
+        let rent_recipient = treasury_account(escrow_info, account_info_iter, program_id)?
+            .unwrap_or(initializers_main_account);
+        msg!("Closing the escrow account...");
+        credit_lamports(escrow_account, rent_recipient, &Rent::get()?)?;
+        escrow_account.try_borrow_mut_data()?.fill(0);
+
+        if let Some(registry_account) =
+            registry_entry(escrow_info, escrow_account.key, account_info_iter, program_id)?
+        {
+            msg!("Closing the registry entry...");
+            credit_lamports(registry_account, initializers_main_account, &Rent::get()?)?;
+            registry_account.try_borrow_mut_data()?.fill(0);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_program::{
+        entrypoint::SUCCESS,
+        instruction::Instruction,
+        program_stubs::{set_syscall_stubs, SyscallStubs},
+    };
+    use std::{cell::{Cell, RefCell}, sync::Once};
+
+    thread_local! {
+        static NOW: Cell<i64> = const { Cell::new(0) };
+        static FAIL_INVOKE: Cell<bool> = const { Cell::new(false) };
+        static INVOKED: RefCell<Vec<Instruction>> = const { RefCell::new(Vec::new()) };
+        static RETURN_DATA: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Answers the sysvar and return data syscalls from the thread locals above and records
+    /// CPIs instead of running them
+    struct TestSyscallStubs;
+
+    impl SyscallStubs for TestSyscallStubs {
+        fn sol_log(&self, _message: &str) {}
+
+        fn sol_invoke_signed(
+            &self,
+            instruction: &Instruction,
+            _account_infos: &[AccountInfo],
+            _signers_seeds: &[&[&[u8]]],
+        ) -> ProgramResult {
+            if FAIL_INVOKE.with(Cell::get) {
+                return Err(ProgramError::Custom(u32::MAX));
+            }
+            INVOKED.with(|invoked| invoked.borrow_mut().push(instruction.clone()));
+            Ok(())
+        }
+
+        fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+            let clock = Clock {
+                unix_timestamp: NOW.with(Cell::get),
+                ..Clock::default()
+            };
+            unsafe { *(var_addr as *mut Clock) = clock };
+            SUCCESS
+        }
+
+        fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+            unsafe { *(var_addr as *mut Rent) = Rent::default() };
+            SUCCESS
+        }
+
+        fn sol_set_return_data(&self, data: &[u8]) {
+            RETURN_DATA.with(|return_data| *return_data.borrow_mut() = data.to_vec());
+        }
+    }
+
+    /// Installs the stubs and resets the thread's clock, CPI log and return data
+    fn setup(now: i64) {
+        static STUBS: Once = Once::new();
+        STUBS.call_once(|| {
+            set_syscall_stubs(Box::new(TestSyscallStubs));
+        });
+        NOW.with(|cell| cell.set(now));
+        FAIL_INVOKE.with(|cell| cell.set(false));
+        INVOKED.with(|invoked| invoked.borrow_mut().clear());
+        RETURN_DATA.with(|return_data| return_data.borrow_mut().clear());
+    }
+
+    fn invoked() -> Vec<Instruction> {
+        INVOKED.with(|invoked| invoked.borrow().clone())
+    }
+
+    fn return_data() -> Vec<u8> {
+        RETURN_DATA.with(|return_data| return_data.borrow().clone())
+    }
+
+    /// Amounts of the token transfers invoked since `setup`
+    fn token_transfers() -> Vec<u64> {
+        use spl_token::instruction::TokenInstruction;
+
+        invoked()
+            .iter()
+            .filter(|instruction| instruction.program_id == spl_token::id())
+            .filter_map(|instruction| match TokenInstruction::unpack(&instruction.data) {
+                Ok(TokenInstruction::Transfer { amount }) => Some(amount),
+                _ => None,
+            })
+            .collect()
+    }
+
+    struct TestAccount {
+        key: Pubkey,
+        is_signer: bool,
+        is_writable: bool,
+        lamports: u64,
+        data: Vec<u8>,
+        owner: Pubkey,
+    }
+
+    impl TestAccount {
+        fn new(key: Pubkey, owner: Pubkey, lamports: u64, data: Vec<u8>) -> Self {
+            Self {
+                key,
+                is_signer: false,
+                is_writable: true,
+                lamports,
+                data,
+                owner,
+            }
+        }
+
+        fn wallet(key: Pubkey) -> Self {
+            Self::new(key, Pubkey::default(), 1_000_000_000, vec![])
+        }
+
+        fn program(key: Pubkey) -> Self {
+            let mut account = Self::new(key, Pubkey::default(), 0, vec![]);
+            account.is_writable = false;
+            account
+        }
+
+        fn escrow(key: Pubkey, program_id: Pubkey, escrow_info: Escrow) -> Self {
+            let mut data = vec![0; Escrow::LEN];
+            Escrow::pack(escrow_info, &mut data).unwrap();
+            Self::new(key, program_id, Rent::default().minimum_balance(Escrow::LEN), data)
+        }
+
+        fn token(key: Pubkey, mint: Pubkey, owner: Pubkey, amount: u64) -> Self {
+            let mut data = vec![0; TokenAccount::LEN];
+            TokenAccount::pack(
+                TokenAccount {
+                    mint,
+                    owner,
+                    amount,
+                    state: spl_token::state::AccountState::Initialized,
+                    ..TokenAccount::default()
+                },
+                &mut data,
+            )
+            .unwrap();
+            Self::new(
+                key,
+                spl_token::id(),
+                Rent::default().minimum_balance(TokenAccount::LEN),
+                data,
+            )
+        }
+
+        fn signer(mut self) -> Self {
+            self.is_signer = true;
+            self
+        }
+
+        fn readonly(mut self) -> Self {
+            self.is_writable = false;
+            self
+        }
+
+        fn info(&mut self) -> AccountInfo<'_> {
+            AccountInfo::new(
+                &self.key,
+                self.is_signer,
+                self.is_writable,
+                &mut self.lamports,
+                &mut self.data,
+                &self.owner,
+                false,
+                0,
+            )
+        }
+    }
+
+    fn process(
+        program_id: &Pubkey,
+        accounts: &mut [TestAccount],
+        instruction: EscrowInstruction,
+    ) -> Result<ProcessOutcome, ProgramError> {
+        let infos = accounts.iter_mut().map(TestAccount::info).collect::<Vec<_>>();
+        Processor::process_with_outcome(program_id, &infos, &instruction.pack())
+    }
+
+    fn escrow_of(account: &TestAccount) -> Escrow {
+        Escrow::unpack(&account.data).unwrap()
+    }
+
+    /// An open token escrow of 1000 tokens with every option off
+    fn token_escrow(initializer: Pubkey, temp_token_account: Pubkey, withdrawer: Pubkey) -> Escrow {
+        let mut escrow_info = Escrow::unpack_unchecked(&[0; Escrow::LEN]).unwrap();
+        escrow_info.is_initialized = true;
+        escrow_info.initializer_pubkey = initializer;
+        escrow_info.temp_token_account_pubkey = temp_token_account;
+        escrow_info.withdrawer_pubkey = withdrawer;
+        escrow_info.deposited_amount = 1000;
+        escrow_info
+    }
+
+    /// The accounts of a token escrow and everything its handlers pass around
+    struct Fixture {
+        program_id: Pubkey,
+        pda: Pubkey,
+        mint: Pubkey,
+        initializer: Pubkey,
+        withdrawer: Pubkey,
+        escrow: Pubkey,
+        temp: Pubkey,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let program_id = Pubkey::new_unique();
+            Self {
+                program_id,
+                pda: Pubkey::find_program_address(&[b"escrow"], &program_id).0,
+                mint: Pubkey::new_unique(),
+                initializer: Pubkey::new_unique(),
+                withdrawer: Pubkey::new_unique(),
+                escrow: Pubkey::new_unique(),
+                temp: Pubkey::new_unique(),
+            }
+        }
+
+        fn escrow_info(&self) -> Escrow {
+            token_escrow(self.initializer, self.temp, self.withdrawer)
+        }
+
+        fn escrow_account(&self, escrow_info: Escrow) -> TestAccount {
+            TestAccount::escrow(self.escrow, self.program_id, escrow_info)
+        }
+
+        fn temp_account(&self, amount: u64) -> TestAccount {
+            TestAccount::token(self.temp, self.mint, self.pda, amount)
+        }
+
+        fn token_account(&self, owner: Pubkey) -> TestAccount {
+            TestAccount::token(Pubkey::new_unique(), self.mint, owner, 0)
+        }
+
+        fn withdraw_accounts(&self, escrow_info: Escrow) -> Vec<TestAccount> {
+            vec![
+                TestAccount::wallet(self.withdrawer).signer(),
+                self.token_account(self.withdrawer),
+                self.temp_account(escrow_info.deposited_amount),
+                TestAccount::wallet(self.initializer),
+                self.escrow_account(escrow_info),
+                TestAccount::program(spl_token::id()),
+                TestAccount::program(self.pda),
+            ]
+        }
+
+        /// Accounts merging an escrow of 500 tokens, 100 more already withdrawn, into
+        /// `escrow_info`
+        fn merge_accounts(&self, escrow_info: Escrow, merged_temp_amount: u64) -> Vec<TestAccount> {
+            let merged_temp = Pubkey::new_unique();
+            let mut merged_escrow_info =
+                token_escrow(self.initializer, merged_temp, self.withdrawer);
+            merged_escrow_info.deposited_amount = 500;
+            merged_escrow_info.total_withdrawn = 100;
+            vec![
+                TestAccount::wallet(self.initializer).signer(),
+                self.escrow_account(escrow_info),
+                self.temp_account(1000),
+                TestAccount::escrow(Pubkey::new_unique(), self.program_id, merged_escrow_info),
+                TestAccount::token(merged_temp, self.mint, self.pda, merged_temp_amount),
+                TestAccount::program(spl_token::id()),
+                TestAccount::program(self.pda),
+            ]
+        }
+
+        /// Accounts of a `CancelEscrow` or `CancelPartial` by the initializer
+        fn cancel_accounts(&self, escrow_info: Escrow) -> Vec<TestAccount> {
+            vec![
+                TestAccount::wallet(self.initializer).signer(),
+                self.escrow_account(escrow_info),
+                self.temp_account(1000),
+                self.token_account(self.initializer),
+                TestAccount::program(spl_token::id()),
+                TestAccount::program(self.pda),
+            ]
+        }
+
+        fn split_accounts(&self, escrow_info: Escrow) -> Vec<TestAccount> {
+            vec![
+                TestAccount::wallet(self.initializer).signer(),
+                self.escrow_account(escrow_info),
+                self.temp_account(1000),
+                self.token_account(self.initializer),
+                TestAccount::wallet(Pubkey::new_unique()).readonly(),
+                TestAccount::new(
+                    Pubkey::new_unique(),
+                    self.program_id,
+                    Rent::default().minimum_balance(Escrow::LEN),
+                    vec![0; Escrow::LEN],
+                ),
+                TestAccount::program(spl_token::id()),
+                TestAccount::program(self.pda),
+            ]
+        }
+    }
+
+    #[test]
+    fn credit_lamports_empties_the_source() {
+        let program_id = Pubkey::new_unique();
+        let rent = Rent::default();
+        let (from_key, to_key) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut from_lamports = rent.minimum_balance(Escrow::LEN) + 5;
+        let mut to_lamports = 7;
+        let (mut from_data, mut to_data) = (vec![0; Escrow::LEN], vec![]);
+        let from = AccountInfo::new(
+            &from_key, false, true, &mut from_lamports, &mut from_data, &program_id, false, 0,
+        );
+        let to = AccountInfo::new(
+            &to_key, false, true, &mut to_lamports, &mut to_data, &program_id, false, 0,
+        );
+
+        credit_lamports(&from, &to, &rent).unwrap();
+
+        assert_eq!(from.lamports(), 0);
+        assert_eq!(to.lamports(), rent.minimum_balance(Escrow::LEN) + 12);
+    }
+
+    #[test]
+    fn credit_lamports_rejects_a_source_below_its_rent() {
+        let program_id = Pubkey::new_unique();
+        let rent = Rent::default();
+        let (from_key, to_key) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut from_lamports = rent.minimum_balance(Escrow::LEN) - 1;
+        let mut to_lamports = 0;
+        let (mut from_data, mut to_data) = (vec![0; Escrow::LEN], vec![]);
+        let from = AccountInfo::new(
+            &from_key, false, true, &mut from_lamports, &mut from_data, &program_id, false, 0,
+        );
+        let to = AccountInfo::new(
+            &to_key, false, true, &mut to_lamports, &mut to_data, &program_id, false, 0,
+        );
+
+        assert_eq!(
+            credit_lamports(&from, &to, &rent),
+            Err(EscrowError::NotRentExempt.into())
+        );
+        assert_eq!(from.lamports(), rent.minimum_balance(Escrow::LEN) - 1);
+        assert_eq!(to.lamports(), 0);
+    }
+
+    #[test]
+    fn init_escrow_requires_the_initializer_signature() {
+        let program_id = Pubkey::new_unique();
+        let key = Pubkey::new_unique();
+        let mut lamports = 0;
+        let mut data = vec![];
+        let initializer =
+            AccountInfo::new(&key, false, true, &mut lamports, &mut data, &key, false, 0);
+
+        let mut instruction_data = vec![0u8; 1 + InitEscrowParams::LEN];
+        instruction_data[1..9].copy_from_slice(&1000u64.to_le_bytes());
+
+        assert_eq!(
+            Processor::process(&program_id, &[initializer], &instruction_data),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+    }
+
+    #[test]
+    fn withdraw_requires_the_taker_signature() {
+        let program_id = Pubkey::new_unique();
+        let (taker_key, receive_key) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let token_program_id = spl_token::id();
+        let (mut taker_lamports, mut receive_lamports) = (0, 0);
+        let (mut taker_data, mut receive_data) = (vec![], vec![0; TokenAccount::LEN]);
+        let taker = AccountInfo::new(
+            &taker_key, false, true, &mut taker_lamports, &mut taker_data, &taker_key, false, 0,
+        );
+        let receive_account = AccountInfo::new(
+            &receive_key,
+            false,
+            true,
+            &mut receive_lamports,
+            &mut receive_data,
+            &token_program_id,
+            false,
+            0,
+        );
+
+        let instruction_data = EscrowInstruction::Withdraw {
+            amount: 1000,
+            preimage: None,
+        }
+        .pack();
+
+        assert_eq!(
+            Processor::process(&program_id, &[taker, receive_account], &instruction_data),
+            Err(ProgramError::MissingRequiredSignature)
+        );
+    }
+
+    #[test]
+    fn withdraw_rejects_a_substituted_temp_token_account() {
+        let program_id = Pubkey::new_unique();
+        let token_program_id = spl_token::id();
+        let system_program_id = Pubkey::default();
+        let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], &program_id);
+        let taker_key = Pubkey::new_unique();
+        let receive_key = Pubkey::new_unique();
+        let attacker_temp_key = Pubkey::new_unique();
+        let initializer_key = Pubkey::new_unique();
+        let escrow_key = Pubkey::new_unique();
+
+        let mut temp_data = vec![0; TokenAccount::LEN];
+        TokenAccount::pack(
+            TokenAccount {
+                mint: Pubkey::new_unique(),
+                owner: pda,
+                amount: 1000,
+                state: spl_token::state::AccountState::Initialized,
+                ..TokenAccount::default()
+            },
+            &mut temp_data,
+        )
+        .unwrap();
+
+        let mut escrow_info = Escrow::unpack_unchecked(&[0; Escrow::LEN]).unwrap();
+        escrow_info.is_initialized = true;
+        escrow_info.initializer_pubkey = initializer_key;
+        escrow_info.temp_token_account_pubkey = Pubkey::new_unique();
+        escrow_info.withdrawer_pubkey = taker_key;
+        escrow_info.deposited_amount = 1000;
+        let mut escrow_data = vec![0; Escrow::LEN];
+        Escrow::pack(escrow_info, &mut escrow_data).unwrap();
+
+        let (mut taker_lamports, mut receive_lamports, mut temp_lamports) = (0, 0, 1);
+        let (mut initializer_lamports, mut escrow_lamports) = (0, 1);
+        let (mut taker_data, mut receive_data, mut initializer_data) =
+            (vec![], vec![0; TokenAccount::LEN], vec![]);
+        let accounts = [
+            AccountInfo::new(
+                &taker_key,
+                true,
+                true,
+                &mut taker_lamports,
+                &mut taker_data,
+                &system_program_id,
+                false,
+                0,
+            ),
+            AccountInfo::new(
+                &receive_key,
+                false,
+                true,
+                &mut receive_lamports,
+                &mut receive_data,
+                &token_program_id,
+                false,
+                0,
+            ),
+            AccountInfo::new(
+                &attacker_temp_key,
+                false,
+                true,
+                &mut temp_lamports,
+                &mut temp_data,
+                &token_program_id,
+                false,
+                0,
+            ),
+            AccountInfo::new(
+                &initializer_key,
+                false,
+                true,
+                &mut initializer_lamports,
+                &mut initializer_data,
+                &system_program_id,
+                false,
+                0,
+            ),
+            AccountInfo::new(
+                &escrow_key,
+                false,
+                true,
+                &mut escrow_lamports,
+                &mut escrow_data,
+                &program_id,
+                false,
+                0,
+            ),
+        ];
+
+        let instruction_data = EscrowInstruction::Withdraw {
+            amount: 1000,
+            preimage: None,
+        }
+        .pack();
+
+        assert_eq!(
+            Processor::process(&program_id, &accounts, &instruction_data),
+            Err(ProgramError::InvalidAccountData)
+        );
+        let temp_account_info = TokenAccount::unpack(&accounts[2].try_borrow_data().unwrap()).unwrap();
+        assert_eq!(temp_account_info.amount, 1000);
+        assert_eq!(
+            Escrow::unpack(&accounts[4].try_borrow_data().unwrap())
+                .unwrap()
+                .deposited_amount,
+            1000
+        );
+    }
+
+    #[test]
+    fn credit_lamports_reports_overflow() {
+        let program_id = Pubkey::new_unique();
+        let rent = Rent::default();
+        let (from_key, to_key) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut from_lamports = rent.minimum_balance(0);
+        let mut to_lamports = u64::MAX;
+        let (mut from_data, mut to_data) = (vec![], vec![]);
+        let from = AccountInfo::new(
+            &from_key, false, true, &mut from_lamports, &mut from_data, &program_id, false, 0,
+        );
+        let to = AccountInfo::new(
+            &to_key, false, true, &mut to_lamports, &mut to_data, &program_id, false, 0,
+        );
+
+        assert_eq!(
+            credit_lamports(&from, &to, &rent),
+            Err(EscrowError::AmountOverflow.into())
+        );
+        assert_eq!(from.lamports(), rent.minimum_balance(0));
+        assert_eq!(to.lamports(), u64::MAX);
+    }
+
+    #[test]
+    fn milestones_must_add_up_to_the_whole_deposit() {
+        let mut data = vec![0u8; InitEscrowParams::LEN];
+        data[..8].copy_from_slice(&1000u64.to_le_bytes());
+        let mut params = InitEscrowParams::from_bytes(&data).unwrap();
+        assert_eq!(assert_valid_milestones(&params), Ok(()));
+
+        params.milestone_count = 2;
+        params.milestones[0].fraction_bps = 4_000;
+        params.milestones[1].fraction_bps = 5_999;
+        assert_eq!(
+            assert_valid_milestones(&params),
+            Err(EscrowError::InvalidMilestones.into())
+        );
+
+        params.milestones[1].fraction_bps = 6_000;
+        assert_eq!(assert_valid_milestones(&params), Ok(()));
+
+        // entries past the count are not part of the schedule
+        params.milestones[2].fraction_bps = 1;
+        assert_eq!(assert_valid_milestones(&params), Ok(()));
+    }
+
+    #[test]
+    fn split_waits_for_the_expiry() {
+        let fixture = Fixture::new();
+        let mut escrow_info = fixture.escrow_info();
+        escrow_info.expiry_unix_timestamp = 100;
+        let mut accounts = fixture.split_accounts(escrow_info);
+
+        setup(99);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::SplitEscrow { amount: 400 }
+            ),
+            Err(EscrowError::EscrowNotExpired.into())
+        );
+        assert_eq!(escrow_of(&accounts[1]).deposited_amount, 1000);
+    }
+
+    #[cfg(not(feature = "escrow-limit"))]
+    #[test]
+    fn split_moves_part_of_the_deposit_into_a_new_escrow() {
+        let fixture = Fixture::new();
+        let mut escrow_info = fixture.escrow_info();
+        escrow_info.expiry_unix_timestamp = 100;
+        let mut accounts = fixture.split_accounts(escrow_info);
+
+        setup(100);
+        let outcome = process(
+            &fixture.program_id,
+            &mut accounts,
+            EscrowInstruction::SplitEscrow { amount: 400 },
+        )
+        .unwrap();
+
+        assert_eq!(outcome, ProcessOutcome::Initialized { escrow: accounts[5].key });
+        assert_eq!(escrow_of(&accounts[1]).deposited_amount, 600);
+        let new_escrow_info = escrow_of(&accounts[5]);
+        assert_eq!(new_escrow_info.deposited_amount, 400);
+        assert_eq!(new_escrow_info.withdrawer_pubkey, accounts[4].key);
+        assert_eq!(new_escrow_info.temp_token_account_pubkey, accounts[3].key);
+        assert_eq!(new_escrow_info.expiry_unix_timestamp, 100);
+    }
+
+    #[test]
+    fn cancel_keeps_a_sealed_escrow_until_its_expiry() {
+        let fixture = Fixture::new();
+        let mut escrow_info = fixture.escrow_info();
+        escrow_info.sealed = true;
+        escrow_info.expiry_unix_timestamp = 100;
+        let mut accounts = vec![
+            TestAccount::wallet(fixture.initializer).signer(),
+            fixture.escrow_account(escrow_info),
+        ];
+
+        setup(99);
+        assert_eq!(
+            process(&fixture.program_id, &mut accounts, EscrowInstruction::CancelEscrow),
+            Err(EscrowError::EscrowSealed.into())
+        );
+        assert!(escrow_of(&accounts[1]).is_initialized);
+    }
+
+    #[cfg(not(feature = "escrow-limit"))]
+    #[test]
+    fn cancel_releases_a_sealed_escrow_once_it_expired() {
+        let fixture = Fixture::new();
+        let mut escrow_info = fixture.escrow_info();
+        escrow_info.sealed = true;
+        escrow_info.expiry_unix_timestamp = 100;
+        let mut accounts = fixture.cancel_accounts(escrow_info);
+
+        setup(100);
+        assert_eq!(
+            process(&fixture.program_id, &mut accounts, EscrowInstruction::CancelEscrow),
+            Ok(ProcessOutcome::Closed)
+        );
+        assert_eq!(accounts[1].lamports, 0);
+    }
+
+    #[test]
+    fn full_only_rejects_a_partial_withdraw() {
+        let fixture = Fixture::new();
+        let mut escrow_info = fixture.escrow_info();
+        escrow_info.full_only = true;
+        let mut accounts = fixture.withdraw_accounts(escrow_info);
+
+        setup(0);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::Withdraw { amount: 400, preimage: None }
+            ),
+            Err(EscrowError::PartialNotAllowed.into())
+        );
+    }
+
+    #[test]
+    fn partial_withdraws_are_accepted_by_default() {
+        let fixture = Fixture::new();
+        let mut accounts = fixture.withdraw_accounts(fixture.escrow_info());
+
+        setup(0);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::Withdraw { amount: 400, preimage: None }
+            ),
+            Ok(ProcessOutcome::Withdrawn { remaining: 600 })
+        );
+        assert_eq!(escrow_of(&accounts[4]).total_withdrawn, 400);
+        assert_eq!(token_transfers(), vec![400]);
+    }
+
+    /// Cuts the escrow account of `withdraw_accounts` down to the original 105 byte layout
+    fn legacy_withdraw_accounts(fixture: &Fixture) -> Vec<TestAccount> {
+        let mut accounts = fixture.withdraw_accounts(fixture.escrow_info());
+        accounts[4].data.truncate(crate::state::LEGACY_ESCROW_LEN);
+        accounts
+    }
+
+    #[cfg(not(feature = "escrow-limit"))]
+    #[test]
+    fn legacy_escrows_can_be_withdrawn_in_full() {
+        let fixture = Fixture::new();
+        let mut accounts = legacy_withdraw_accounts(&fixture);
+        assert_eq!(escrow_of(&accounts[4]).deposited_amount, 1000);
+
+        setup(0);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::Withdraw { amount: 1000, preimage: None }
+            ),
+            Ok(ProcessOutcome::Closed)
+        );
+        assert_eq!(accounts[4].lamports, 0);
+    }
+
+    #[test]
+    fn legacy_escrows_have_no_room_for_a_partial_withdraw() {
+        let fixture = Fixture::new();
+        let mut accounts = legacy_withdraw_accounts(&fixture);
+        let data = accounts[4].data.clone();
+
+        setup(0);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::Withdraw { amount: 400, preimage: None }
+            ),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+        assert_eq!(accounts[4].data, data);
+    }
+
+    #[test]
+    fn an_over_draw_leaves_the_escrow_untouched() {
+        let fixture = Fixture::new();
+        let mut accounts = fixture.withdraw_accounts(fixture.escrow_info());
+        let data = accounts[4].data.clone();
+
+        setup(0);
+        assert!(process(
+            &fixture.program_id,
+            &mut accounts,
+            EscrowInstruction::Withdraw { amount: 1001, preimage: None }
+        )
+        .is_err());
+        assert_eq!(accounts[4].data, data);
+        assert!(invoked().is_empty());
+    }
+
+    #[test]
+    fn withdraw_rejects_an_escrow_owned_by_another_program() {
+        let fixture = Fixture::new();
+        let mut accounts = fixture.withdraw_accounts(fixture.escrow_info());
+        accounts[4].owner = Pubkey::new_unique();
+
+        setup(0);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::Withdraw { amount: 1000, preimage: None }
+            ),
+            Err(ProgramError::IncorrectProgramId)
+        );
+    }
+
+    #[test]
+    fn a_failed_transfer_leaves_the_escrow_untouched() {
+        let fixture = Fixture::new();
+        let mut accounts = fixture.withdraw_accounts(fixture.escrow_info());
+        let data = accounts[4].data.clone();
+
+        setup(0);
+        FAIL_INVOKE.with(|cell| cell.set(true));
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::Withdraw { amount: 400, preimage: None }
+            ),
+            Err(ProgramError::Custom(u32::MAX))
+        );
+        assert_eq!(accounts[4].data, data);
+    }
+
+    #[test]
+    fn credit_lamports_funds_an_empty_destination() {
+        let program_id = Pubkey::new_unique();
+        let rent = Rent::default();
+        let (from_key, to_key) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut from_lamports = rent.minimum_balance(Escrow::LEN);
+        let mut to_lamports = 0;
+        let (mut from_data, mut to_data) = (vec![0; Escrow::LEN], vec![]);
+        let system_program = Pubkey::default();
+        let from = AccountInfo::new(
+            &from_key, false, true, &mut from_lamports, &mut from_data, &program_id, false, 0,
+        );
+        let to = AccountInfo::new(
+            &to_key, false, true, &mut to_lamports, &mut to_data, &system_program, false, 0,
+        );
+
+        credit_lamports(&from, &to, &rent).unwrap();
+
+        assert_eq!(from.lamports(), 0);
+        assert_eq!(to.lamports(), rent.minimum_balance(Escrow::LEN));
+    }
+
+    #[cfg(not(feature = "escrow-limit"))]
+    #[test]
+    fn closing_pays_the_rent_to_an_unfunded_initializer() {
+        let fixture = Fixture::new();
+        let mut accounts = fixture.withdraw_accounts(fixture.escrow_info());
+        accounts[3].lamports = 0;
+
+        setup(0);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::Withdraw { amount: 1000, preimage: None }
+            ),
+            Ok(ProcessOutcome::Closed)
+        );
+        assert_eq!(accounts[3].lamports, Rent::default().minimum_balance(Escrow::LEN));
+        assert_eq!(accounts[4].lamports, 0);
+    }
+
+    #[test]
+    fn assert_state_accepts_a_matching_escrow() {
+        let fixture = Fixture::new();
+        let mut accounts = vec![fixture.escrow_account(fixture.escrow_info()).readonly()];
+
+        setup(0);
+        for min_remaining in [0, 1000] {
+            assert_eq!(
+                process(
+                    &fixture.program_id,
+                    &mut accounts,
+                    EscrowInstruction::AssertState {
+                        expected_withdrawer: fixture.withdrawer,
+                        min_remaining,
+                    }
+                ),
+                Ok(ProcessOutcome::Unchanged)
+            );
+        }
+    }
+
+    #[test]
+    fn assert_state_rejects_another_withdrawer_or_a_smaller_deposit() {
+        let fixture = Fixture::new();
+        let mut accounts = vec![fixture.escrow_account(fixture.escrow_info()).readonly()];
+
+        setup(0);
+        for (expected_withdrawer, min_remaining) in
+            [(Pubkey::new_unique(), 0), (fixture.withdrawer, 1001)]
+        {
+            assert_eq!(
+                process(
+                    &fixture.program_id,
+                    &mut accounts,
+                    EscrowInstruction::AssertState { expected_withdrawer, min_remaining }
+                ),
+                Err(EscrowError::StateAssertionFailed.into())
+            );
+        }
+    }
+
+    #[test]
+    fn assert_state_rejects_a_closed_escrow() {
+        let fixture = Fixture::new();
+        let mut accounts = vec![fixture.escrow_account(fixture.escrow_info()).readonly()];
+        accounts[0].data.fill(0);
+
+        setup(0);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::AssertState {
+                    expected_withdrawer: fixture.withdrawer,
+                    min_remaining: 0,
+                }
+            ),
+            Err(ProgramError::UninitializedAccount)
+        );
+    }
+
+    #[test]
+    fn split_leaves_something_in_the_original_escrow() {
+        let fixture = Fixture::new();
+        let mut accounts = fixture.split_accounts(fixture.escrow_info());
+
+        setup(0);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::SplitEscrow { amount: 1000 }
+            ),
+            Err(EscrowError::ExpectedAmountMismatch.into())
+        );
+        assert_eq!(escrow_of(&accounts[1]).deposited_amount, 1000);
+        assert_eq!(accounts[5].data, vec![0; Escrow::LEN]);
+    }
+
+    #[test]
+    fn split_rejects_a_signing_new_withdrawer() {
+        let fixture = Fixture::new();
+        let mut accounts = fixture.split_accounts(fixture.escrow_info());
+        accounts[4].is_signer = true;
+
+        setup(0);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::SplitEscrow { amount: 400 }
+            ),
+            Err(ProgramError::InvalidArgument)
+        );
+    }
+
+    #[cfg(not(feature = "escrow-limit"))]
+    #[test]
+    fn split_transfers_the_amount_to_the_new_temp_account() {
+        let fixture = Fixture::new();
+        let mut accounts = fixture.split_accounts(fixture.escrow_info());
+
+        setup(0);
+        process(
+            &fixture.program_id,
+            &mut accounts,
+            EscrowInstruction::SplitEscrow { amount: 400 },
+        )
+        .unwrap();
+
+        assert_eq!(token_transfers(), vec![400]);
+    }
+
+    #[cfg(not(feature = "escrow-limit"))]
+    #[test]
+    fn merge_moves_the_deposit_and_closes_the_merged_escrow() {
+        use spl_token::instruction::TokenInstruction;
+
+        let fixture = Fixture::new();
+        let mut accounts = fixture.merge_accounts(fixture.escrow_info(), 500);
+
+        setup(0);
+        assert_eq!(
+            process(&fixture.program_id, &mut accounts, EscrowInstruction::MergeEscrow),
+            Ok(ProcessOutcome::Updated { remaining: 1500 })
+        );
+        assert_eq!(token_transfers(), vec![500]);
+        assert!(matches!(
+            TokenInstruction::unpack(&invoked().last().unwrap().data),
+            Ok(TokenInstruction::CloseAccount)
+        ));
+        assert_eq!(escrow_of(&accounts[1]).total_withdrawn, 100);
+        assert_eq!(accounts[3].lamports, 0);
+    }
+
+    #[cfg(not(feature = "escrow-limit"))]
+    #[test]
+    fn merge_hands_a_surplus_back_with_the_temp_account() {
+        use spl_token::instruction::TokenInstruction;
+
+        let fixture = Fixture::new();
+        let mut accounts = fixture.merge_accounts(fixture.escrow_info(), 700);
+
+        setup(0);
+        assert_eq!(
+            process(&fixture.program_id, &mut accounts, EscrowInstruction::MergeEscrow),
+            Ok(ProcessOutcome::Updated { remaining: 1500 })
+        );
+        assert_eq!(token_transfers(), vec![500]);
+        assert!(matches!(
+            TokenInstruction::unpack(&invoked().last().unwrap().data),
+            Ok(TokenInstruction::SetAuthority { new_authority, .. })
+                if new_authority == Some(fixture.initializer).into()
+        ));
+    }
+
+    #[test]
+    fn merge_rejects_escrows_with_another_reserve() {
+        let fixture = Fixture::new();
+        let mut escrow_info = fixture.escrow_info();
+        escrow_info.reserve_amount = 10;
+        let mut accounts = fixture.merge_accounts(escrow_info, 500);
+
+        setup(0);
+        assert_eq!(
+            process(&fixture.program_id, &mut accounts, EscrowInstruction::MergeEscrow),
+            Err(EscrowError::IncompatibleEscrows.into())
+        );
+        assert!(invoked().is_empty());
+    }
+
+    #[test]
+    fn withdraw_may_take_the_deposit_down_to_the_reserve() {
+        let fixture = Fixture::new();
+        let mut escrow_info = fixture.escrow_info();
+        escrow_info.reserve_amount = 100;
+        let mut accounts = fixture.withdraw_accounts(escrow_info);
+
+        setup(0);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::Withdraw { amount: 900, preimage: None }
+            ),
+            Ok(ProcessOutcome::Withdrawn { remaining: 100 })
+        );
+    }
+
+    #[test]
+    fn withdraw_rejects_going_below_the_reserve() {
+        let fixture = Fixture::new();
+        let mut escrow_info = fixture.escrow_info();
+        escrow_info.reserve_amount = 100;
+        let mut accounts = fixture.withdraw_accounts(escrow_info);
+
+        setup(0);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::Withdraw { amount: 901, preimage: None }
+            ),
+            Err(EscrowError::BelowReserve.into())
+        );
+    }
+
+    #[test]
+    fn withdraw_waits_for_the_cooldown() {
+        let fixture = Fixture::new();
+        let mut escrow_info = fixture.escrow_info();
+        escrow_info.withdraw_cooldown_secs = 60;
+        escrow_info.last_withdraw_unix_timestamp = 1_000;
+        let mut accounts = fixture.withdraw_accounts(escrow_info);
+
+        setup(1_059);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::Withdraw { amount: 400, preimage: None }
+            ),
+            Err(EscrowError::WithdrawCooldownActive.into())
+        );
+
+        setup(1_060);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::Withdraw { amount: 400, preimage: None }
+            ),
+            Ok(ProcessOutcome::Withdrawn { remaining: 600 })
+        );
+        assert_eq!(escrow_of(&accounts[4]).last_withdraw_unix_timestamp, 1_060);
+    }
+
+    #[test]
+    fn withdraw_rejects_more_than_the_deposit_even_with_a_larger_balance() {
+        let fixture = Fixture::new();
+        let mut accounts = fixture.withdraw_accounts(fixture.escrow_info());
+        accounts[2] = fixture.temp_account(1500);
+
+        setup(0);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::Withdraw { amount: 1001, preimage: None }
+            ),
+            Err(EscrowError::InsufficientEscrowBalance.into())
+        );
+    }
+
+    #[test]
+    fn withdraw_accepts_up_to_the_deposit_with_a_larger_balance() {
+        let fixture = Fixture::new();
+        let mut accounts = fixture.withdraw_accounts(fixture.escrow_info());
+        accounts[2] = fixture.temp_account(1500);
+
+        setup(0);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::Withdraw { amount: 999, preimage: None }
+            ),
+            Ok(ProcessOutcome::Withdrawn { remaining: 1 })
+        );
+    }
+
+    #[cfg(not(feature = "escrow-limit"))]
+    #[test]
+    fn a_closed_escrow_cannot_be_closed_again() {
+        let fixture = Fixture::new();
+        let mut accounts = fixture.cancel_accounts(fixture.escrow_info());
+
+        setup(0);
+        assert_eq!(
+            process(&fixture.program_id, &mut accounts, EscrowInstruction::CancelEscrow),
+            Ok(ProcessOutcome::Closed)
+        );
+        assert_eq!(
+            process(&fixture.program_id, &mut accounts, EscrowInstruction::CancelEscrow),
+            Err(EscrowError::EscrowAlreadyClosed.into())
+        );
+    }
+
+    #[test]
+    fn withdraw_rejects_a_zeroed_escrow() {
+        let fixture = Fixture::new();
+        let mut accounts = fixture.withdraw_accounts(fixture.escrow_info());
+        accounts[4].data.fill(0);
+
+        setup(0);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::Withdraw { amount: 400, preimage: None }
+            ),
+            Err(EscrowError::EscrowAlreadyClosed.into())
+        );
+    }
+
+    #[test]
+    fn withdraw_from_a_hash_locked_escrow_needs_the_preimage() {
+        let fixture = Fixture::new();
+        let mut escrow_info = fixture.escrow_info();
+        escrow_info.hashlock = hash(&[7; 32]).to_bytes();
+        let mut accounts = fixture.withdraw_accounts(escrow_info);
+
+        setup(0);
+        for preimage in [None, Some([8; 32])] {
+            assert_eq!(
+                process(
+                    &fixture.program_id,
+                    &mut accounts,
+                    EscrowInstruction::Withdraw { amount: 400, preimage }
+                ),
+                Err(EscrowError::InvalidPreimage.into())
+            );
+        }
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::Withdraw { amount: 400, preimage: Some([7; 32]) }
+            ),
+            Ok(ProcessOutcome::Withdrawn { remaining: 600 })
+        );
+    }
+
+    #[test]
+    fn withdraw_stops_at_the_expiry() {
+        let fixture = Fixture::new();
+        let mut escrow_info = fixture.escrow_info();
+        escrow_info.expiry_unix_timestamp = 100;
+        let mut accounts = fixture.withdraw_accounts(escrow_info);
+
+        setup(99);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::Withdraw { amount: 400, preimage: None }
+            ),
+            Ok(ProcessOutcome::Withdrawn { remaining: 600 })
+        );
+
+        setup(100);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::Withdraw { amount: 400, preimage: None }
+            ),
+            Err(EscrowError::EscrowExpired.into())
+        );
+    }
+
+    #[test]
+    fn cancel_waits_for_the_expiry() {
+        let fixture = Fixture::new();
+        let mut escrow_info = fixture.escrow_info();
+        escrow_info.expiry_unix_timestamp = 100;
+        let mut accounts = vec![
+            TestAccount::wallet(fixture.initializer).signer(),
+            fixture.escrow_account(escrow_info),
+        ];
+
+        setup(99);
+        assert_eq!(
+            process(&fixture.program_id, &mut accounts, EscrowInstruction::CancelEscrow),
+            Err(EscrowError::EscrowNotExpired.into())
+        );
+    }
+
+    #[test]
+    fn seal_is_for_the_initializer_and_only_once() {
+        let fixture = Fixture::new();
+        let mut accounts = vec![
+            TestAccount::wallet(fixture.withdrawer).signer(),
+            fixture.escrow_account(fixture.escrow_info()),
+        ];
+
+        setup(0);
+        assert_eq!(
+            process(&fixture.program_id, &mut accounts, EscrowInstruction::Seal),
+            Err(ProgramError::InvalidAccountData)
+        );
+
+        accounts[0] = TestAccount::wallet(fixture.initializer).signer();
+        assert_eq!(
+            process(&fixture.program_id, &mut accounts, EscrowInstruction::Seal),
+            Ok(ProcessOutcome::Updated { remaining: 1000 })
+        );
+        assert!(escrow_of(&accounts[1]).sealed);
+        assert_eq!(
+            process(&fixture.program_id, &mut accounts, EscrowInstruction::Seal),
+            Err(EscrowError::EscrowSealed.into())
+        );
+    }
+
+    #[test]
+    fn a_sealed_escrow_cannot_be_split() {
+        let fixture = Fixture::new();
+        let mut escrow_info = fixture.escrow_info();
+        escrow_info.sealed = true;
+        let mut accounts = fixture.split_accounts(escrow_info);
+
+        setup(0);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::SplitEscrow { amount: 400 }
+            ),
+            Err(EscrowError::EscrowSealed.into())
+        );
+    }
+
+    #[test]
+    fn cancel_partial_returns_part_of_the_deposit() {
+        let fixture = Fixture::new();
+        let mut accounts = fixture.cancel_accounts(fixture.escrow_info());
+
+        setup(0);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::CancelPartial { amount: 400 }
+            ),
+            Ok(ProcessOutcome::Updated { remaining: 600 })
+        );
+        assert_eq!(token_transfers(), vec![400]);
+        assert_eq!(escrow_of(&accounts[1]).deposited_amount, 600);
+    }
+
+    #[test]
+    fn cancel_partial_leaves_something_in_the_escrow() {
+        let fixture = Fixture::new();
+        let mut accounts = fixture.cancel_accounts(fixture.escrow_info());
+
+        setup(0);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::CancelPartial { amount: 1000 }
+            ),
+            Err(EscrowError::ExpectedAmountMismatch.into())
+        );
+        assert!(invoked().is_empty());
+    }
+
+    #[cfg(not(feature = "escrow-limit"))]
+    #[test]
+    fn outcomes_follow_the_escrow_through_its_life() {
+        let fixture = Fixture::new();
+        let mut init_accounts = vec![
+            TestAccount::wallet(fixture.initializer).signer(),
+            TestAccount::token(fixture.temp, fixture.mint, fixture.initializer, 1000),
+            TestAccount::wallet(fixture.withdrawer).readonly(),
+            TestAccount::new(
+                fixture.escrow,
+                fixture.program_id,
+                Rent::default().minimum_balance(Escrow::LEN),
+                vec![0; Escrow::LEN],
+            ),
+            TestAccount::program(spl_token::id()),
+        ];
+
+        setup(0);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut init_accounts,
+                EscrowInstruction::InitEscrow(InitEscrowParams::new(1000))
+            ),
+            Ok(ProcessOutcome::Initialized { escrow: fixture.escrow })
+        );
+        let escrow_info = escrow_of(&init_accounts[3]);
+
+        let mut accounts = fixture.withdraw_accounts(escrow_info);
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::Withdraw { amount: 400, preimage: None }
+            ),
+            Ok(ProcessOutcome::Withdrawn { remaining: 600 })
+        );
+
+        let mut cancel_accounts = fixture.cancel_accounts(escrow_of(&accounts[4]));
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut cancel_accounts,
+                EscrowInstruction::CancelPartial { amount: 100 }
+            ),
+            Ok(ProcessOutcome::Updated { remaining: 500 })
+        );
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut cancel_accounts[1..2],
+                EscrowInstruction::AssertState {
+                    expected_withdrawer: fixture.withdrawer,
+                    min_remaining: 500,
+                }
+            ),
+            Ok(ProcessOutcome::Unchanged)
+        );
+
+        let mut accounts = fixture.withdraw_accounts(escrow_of(&cancel_accounts[1]));
+        assert_eq!(
+            process(
+                &fixture.program_id,
+                &mut accounts,
+                EscrowInstruction::Withdraw { amount: 500, preimage: None }
+            ),
+            Ok(ProcessOutcome::Closed)
+        );
+    }
+
+    #[test]
+    fn process_reports_success_without_the_outcome() {
+        let fixture = Fixture::new();
+        let mut accounts = [fixture.escrow_account(fixture.escrow_info()).readonly()];
+        let infos = accounts.iter_mut().map(TestAccount::info).collect::<Vec<_>>();
+        let instruction = EscrowInstruction::AssertState {
+            expected_withdrawer: fixture.withdrawer,
+            min_remaining: 0,
+        };
+
+        setup(0);
+        assert_eq!(
+            Processor::process(&fixture.program_id, &infos, &instruction.pack()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn state_hash_returns_the_hash_of_the_escrow() {
+        let fixture = Fixture::new();
+        let mut accounts = fixture.withdraw_accounts(fixture.escrow_info());
+
+        setup(0);
+        assert_eq!(
+            process(&fixture.program_id, &mut accounts[4..5], EscrowInstruction::StateHash),
+            Ok(ProcessOutcome::Unchanged)
+        );
+        let before = return_data();
+        assert_eq!(before, fixture.escrow_info().state_hash().to_bytes());
+
+        process(
+            &fixture.program_id,
+            &mut accounts,
+            EscrowInstruction::Withdraw { amount: 400, preimage: None },
+        )
+        .unwrap();
+        process(&fixture.program_id, &mut accounts[4..5], EscrowInstruction::StateHash).unwrap();
+        assert_ne!(return_data(), before);
+        assert_eq!(return_data(), escrow_of(&accounts[4]).state_hash().to_bytes());
+    }
+
+    #[test]
+    fn state_hash_rejects_a_foreign_escrow() {
+        let fixture = Fixture::new();
+        let mut accounts = [fixture.escrow_account(fixture.escrow_info())];
+        accounts[0].owner = Pubkey::new_unique();
+
+        setup(0);
+        assert_eq!(
+            process(&fixture.program_id, &mut accounts, EscrowInstruction::StateHash),
+            Err(ProgramError::IncorrectProgramId)
+        );
+        assert!(return_data().is_empty());
+    }
+
+    #[test]
+    fn init_with_exact_rent_rejects_a_prefunded_escrow_account() {
+        let fixture = Fixture::new();
+        let mut params = InitEscrowParams::new(1000);
+        params.require_exact_rent = true;
+        let mut accounts = vec![
+            TestAccount::wallet(fixture.initializer).signer(),
+            TestAccount::token(fixture.temp, fixture.mint, fixture.initializer, 1000),
+            TestAccount::wallet(fixture.withdrawer).readonly(),
+            TestAccount::new(
+                fixture.escrow,
+                fixture.program_id,
+                Rent::default().minimum_balance(Escrow::LEN) + 1,
+                vec![0; Escrow::LEN],
+            ),
+            TestAccount::program(spl_token::id()),
+        ];
+
+        setup(0);
+        assert_eq!(
+            process(&fixture.program_id, &mut accounts, EscrowInstruction::InitEscrow(params)),
+            Err(EscrowError::UnexpectedLamports.into())
+        );
     }
 }
@@ -1,17 +1,207 @@
 use solana_program::{
+    hash::{hashv, Hash},
     program_error::ProgramError,
     program_pack::{IsInitialized, Pack, Sealed},
     pubkey::Pubkey,
+    sysvar::rent::Rent,
 };
 
 use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
 
+use crate::{error::EscrowError, math};
+
+/// Most milestones an escrow can hold
+pub const MAX_MILESTONES: usize = 4;
+
+/// Basis points making up the whole deposit
+pub const TOTAL_BPS: u16 = 10_000;
+
+/// A tranche of the deposit that unlocks at a given time
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Milestone {
+    pub unlock_unix_timestamp: i64,
+    /// Share of the deposit released at `unlock_unix_timestamp`, in basis points
+    pub fraction_bps: u16,
+}
+
+impl Milestone {
+    pub const LEN: usize = 10;
+
+    pub fn unpack_from_slice(src: &[u8; Milestone::LEN]) -> Self {
+        let (unlock_unix_timestamp, fraction_bps) = array_refs![src, 8, 2];
+        Milestone {
+            unlock_unix_timestamp: i64::from_le_bytes(*unlock_unix_timestamp),
+            fraction_bps: u16::from_le_bytes(*fraction_bps),
+        }
+    }
+
+    pub fn pack_into_slice(&self, dst: &mut [u8; Milestone::LEN]) {
+        let (unlock_unix_timestamp_dst, fraction_bps_dst) = mut_array_refs![dst, 8, 2];
+        *unlock_unix_timestamp_dst = self.unlock_unix_timestamp.to_le_bytes();
+        *fraction_bps_dst = self.fraction_bps.to_le_bytes();
+    }
+}
+
+/// Where an escrow is in its lifecycle, see [Escrow::status](struct.Escrow.html#method.status).
+/// The discriminant is the status byte of the withdraw return data.
+///
+/// There is no `Paused` status as escrows can't be paused, a `Seal` only locks the initializer
+/// out of changing them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EscrowStatus {
+    /// Nothing has been withdrawn yet
+    Open = 0,
+    /// Some tokens were withdrawn and some remain
+    PartiallyWithdrawn = 1,
+    /// Nothing remains to withdraw
+    Closed = 2,
+    /// The expiry passed with tokens left, only the initializer can take them back
+    Expired = 3,
+}
+
 pub struct Escrow {
     pub is_initialized: bool,
     pub initializer_pubkey: Pubkey,
     pub temp_token_account_pubkey: Pubkey,
     pub withdrawer_pubkey: Pubkey,
     pub deposited_amount: u64,
+    pub freeze_mode: bool,
+    pub reserve_amount: u64,
+    pub total_withdrawn: u64,
+    /// Number of entries of `milestones` in use, zero means the whole deposit is unlocked
+    pub milestone_count: u8,
+    pub milestones: [Milestone; MAX_MILESTONES],
+    /// The escrow account itself holds `deposited_amount` lamports, there is no temp token
+    /// account
+    pub lamport_mode: bool,
+    /// Only withdraws of the whole `deposited_amount` are accepted, zero when partial ones are
+    /// too
+    pub full_only: bool,
+    /// Key whose ed25519 signature every withdraw needs, `Pubkey::default()` when unrestricted
+    pub authority_pubkey: Pubkey,
+    /// Remainders below this many tokens go to a dust collector instead of the initializer when
+    /// the escrow is declined or cancelled, `0` to always return them
+    pub dust_threshold: u64,
+    /// When the last partial withdraw happened, `0` before the first one
+    pub last_withdraw_unix_timestamp: i64,
+    /// Minimum number of seconds between two withdraws
+    pub withdraw_cooldown_secs: u32,
+    /// Closing the escrow sends its rent to the treasury PDA instead of the initializer
+    pub rent_to_treasury: bool,
+    /// Withdraws must pay into token accounts owned by the withdrawer
+    pub require_receive_owner: bool,
+    /// SHA-256 hash whose preimage every withdraw has to present, all zeroes when unlocked
+    pub hashlock: [u8; 32],
+    /// Withdraws stop and the initializer may cancel from this time on, `0` for no expiry
+    pub expiry_unix_timestamp: i64,
+    /// Off-chain order id set by the initializer, purely informational
+    pub reference_id: u64,
+    /// Set once by `Seal`, after which the initializer can no longer split, merge or drain
+    pub sealed: bool,
+    /// Withdraws pay into the taker's associated token account, creating it when missing
+    pub create_receive_account: bool,
+    /// Number of partial withdraws so far, part of the message the authority signs so that no
+    /// signature authorizes more than one withdraw
+    pub withdraw_nonce: u64,
+    /// Token account the remainders below `dust_threshold` go to. `Pubkey::default()` on escrows
+    /// from before it was stored, their dust goes to the initializer
+    pub dust_collector_pubkey: Pubkey,
+    /// Initialized with `use_registry`, closing the escrow closes its registry entry too
+    pub registered: bool,
+    /// Unused space kept at the end of the account so new fields can be added without
+    /// changing `Escrow::LEN`. It is sized once, a new field takes its bytes from the front of
+    /// it and this shrinks by as much
+    pub reserved: [u8; 202],
+}
+
+impl Escrow {
+    /// Status of the escrow at `now_unix`
+    pub fn status(&self, now_unix: i64) -> EscrowStatus {
+        if !self.is_initialized || self.deposited_amount == 0 {
+            EscrowStatus::Closed
+        } else if self.is_expired(now_unix) {
+            EscrowStatus::Expired
+        } else if self.total_withdrawn > 0 {
+            EscrowStatus::PartiallyWithdrawn
+        } else {
+            EscrowStatus::Open
+        }
+    }
+
+    /// Whether the escrow has an expiry and it passed at `now_unix`
+    pub fn is_expired(&self, now_unix: i64) -> bool {
+        self.expiry_unix_timestamp != 0 && now_unix >= self.expiry_unix_timestamp
+    }
+
+    /// Amount the withdrawer may take out at `now_unix`.
+    ///
+    /// Without milestones the whole remaining deposit is claimable. Otherwise it is the share of
+    /// everything ever deposited (remaining plus withdrawn) unlocked by the milestones reached,
+    /// minus what was already withdrawn. This is the single place the schedule is applied so
+    /// that the processor and clients agree on the number.
+    ///
+    /// The arithmetic saturates, the result never exceeds `deposited_amount`.
+    pub fn claimable_amount(&self, now_unix: i64) -> u64 {
+        if self.milestone_count == 0 {
+            return self.deposited_amount;
+        }
+
+        let unlocked_bps = self.milestones[..self.milestone_count as usize]
+            .iter()
+            .filter(|milestone| milestone.unlock_unix_timestamp <= now_unix)
+            .fold(0u16, |bps, milestone| bps.saturating_add(milestone.fraction_bps))
+            .min(TOTAL_BPS);
+        let total = self.deposited_amount.saturating_add(self.total_withdrawn);
+        // the share is capped at the whole deposit, so the result never exceeds `total`
+        let unlocked =
+            math::mul_div(total, unlocked_bps as u64, TOTAL_BPS as u64).unwrap_or(total);
+
+        unlocked
+            .saturating_sub(self.total_withdrawn)
+            .min(self.deposited_amount)
+    }
+
+    /// Hash of the packed escrow. Any change to the escrow changes it, so clients can tell
+    /// whether an escrow moved on between reading it and acting on it.
+    pub fn state_hash(&self) -> Hash {
+        let mut data = [0u8; Escrow::LEN];
+        self.pack_into_slice(&mut data);
+        hashv(&[&data])
+    }
+}
+
+/// Size of the SPL token account used as the escrow's temp token account, for sizing its
+/// `create_account` instruction
+pub const TEMP_TOKEN_ACCOUNT_LEN: usize = spl_token::state::Account::LEN;
+
+/// Size of a registry entry: the pubkey of the escrow created for its
+/// `[b"registry", initializer, mint, withdrawer]` seeds
+pub const REGISTRY_LEN: usize = 32;
+
+/// Most escrows a single initializer may have open at once, enforced with the `escrow-limit`
+/// feature
+pub const MAX_ESCROWS_PER_USER: u32 = 8;
+
+/// Size of an initializer's open escrow counter: a little-endian `u32` stored in the PDA
+/// derived from `[b"counter", initializer]`
+pub const COUNTER_LEN: usize = 4;
+
+/// Size of the original `Escrow` layout, which ends after `deposited_amount` and which live
+/// escrow accounts may still have. Fields were only ever added at the end, so such an account
+/// reads as the current layout with the missing tail zeroed. It can't store anything past that
+/// though: packing fails with `AccountDataTooSmall` once a later field is set, which rules out
+/// partial withdraws as they record `total_withdrawn`. These escrows can still be withdrawn in
+/// full, declined or cancelled.
+pub const LEGACY_ESCROW_LEN: usize = 105;
+
+/// Lamports the escrow account needs to be rent exempt
+pub fn escrow_rent(rent: &Rent) -> u64 {
+    rent.minimum_balance(Escrow::LEN)
+}
+
+/// Lamports the temp token account needs to be rent exempt
+pub fn temp_token_account_rent(rent: &Rent) -> u64 {
+    rent.minimum_balance(TEMP_TOKEN_ACCOUNT_LEN)
 }
 
 impl Sealed for Escrow {}
@@ -23,7 +213,41 @@ impl IsInitialized for Escrow {
 }
 
 impl Pack for Escrow {
-    const LEN: usize = 105;
+    const LEN: usize = 512;
+
+    fn unpack_unchecked(input: &[u8]) -> Result<Self, ProgramError> {
+        if input.len() == Escrow::LEN {
+            return Self::unpack_from_slice(input);
+        }
+        if input.len() != LEGACY_ESCROW_LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let mut data = [0u8; Escrow::LEN];
+        data[..input.len()].copy_from_slice(input);
+        Self::unpack_from_slice(&data)
+    }
+
+    fn pack(src: Self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if dst.len() == Escrow::LEN {
+            src.pack_into_slice(dst);
+            return Ok(());
+        }
+        if dst.len() != LEGACY_ESCROW_LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let mut data = [0u8; Escrow::LEN];
+        src.pack_into_slice(&mut data);
+        // an account of the original layout has no room for the fields added after it
+        let (data, tail) = data.split_at(dst.len());
+        if tail.iter().any(|&byte| byte != 0) {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+        dst.copy_from_slice(data);
+        Ok(())
+    }
+
     fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
         let src = array_ref![src, 0, Escrow::LEN];
         let (
@@ -32,12 +256,85 @@ impl Pack for Escrow {
             temp_token_account_pubkey,
             withdrawer_pubkey,
             deposited_amount,
-        ) = array_refs![src, 1, 32, 32, 32, 8];
+            freeze_mode,
+            reserve_amount,
+            total_withdrawn,
+            milestone_count,
+            milestones_src,
+            lamport_mode,
+            full_only,
+            authority_pubkey,
+            dust_threshold,
+            last_withdraw_unix_timestamp,
+            withdraw_cooldown_secs,
+            rent_to_treasury,
+            require_receive_owner,
+            hashlock,
+            expiry_unix_timestamp,
+            reference_id,
+            sealed,
+            create_receive_account,
+            withdraw_nonce,
+            dust_collector_pubkey,
+            registered,
+            reserved,
+        ) = array_refs![src, 1, 32, 32, 32, 8, 1, 8, 8, 1, Milestone::LEN * MAX_MILESTONES, 1, 1, 32, 8, 8, 4, 1, 1, 32, 8, 8, 1, 1, 8, 32, 1, 202];
         let is_initialized = match is_initialized {
             [0] => false,
             [1] => true,
             _ => return Err(ProgramError::InvalidAccountData),
         };
+        let freeze_mode = match freeze_mode {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let lamport_mode = match lamport_mode {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let full_only = match full_only {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let rent_to_treasury = match rent_to_treasury {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let require_receive_owner = match require_receive_owner {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let sealed = match sealed {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let create_receive_account = match create_receive_account {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let registered = match registered {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        if milestone_count[0] as usize > MAX_MILESTONES {
+            return Err(EscrowError::TooManyEntries.into());
+        }
+        let mut milestones = [Milestone::default(); MAX_MILESTONES];
+        for (i, milestone) in milestones.iter_mut().enumerate() {
+            *milestone = Milestone::unpack_from_slice(array_ref![
+                milestones_src,
+                i * Milestone::LEN,
+                Milestone::LEN
+            ]);
+        }
 
         Ok(Escrow {
             is_initialized,
@@ -47,6 +344,28 @@ impl Pack for Escrow {
                 *withdrawer_pubkey,
             ),
             deposited_amount: u64::from_le_bytes(*deposited_amount),
+            freeze_mode,
+            reserve_amount: u64::from_le_bytes(*reserve_amount),
+            total_withdrawn: u64::from_le_bytes(*total_withdrawn),
+            milestone_count: milestone_count[0],
+            milestones,
+            lamport_mode,
+            full_only,
+            authority_pubkey: Pubkey::new_from_array(*authority_pubkey),
+            dust_threshold: u64::from_le_bytes(*dust_threshold),
+            last_withdraw_unix_timestamp: i64::from_le_bytes(*last_withdraw_unix_timestamp),
+            withdraw_cooldown_secs: u32::from_le_bytes(*withdraw_cooldown_secs),
+            rent_to_treasury,
+            require_receive_owner,
+            hashlock: *hashlock,
+            expiry_unix_timestamp: i64::from_le_bytes(*expiry_unix_timestamp),
+            reference_id: u64::from_le_bytes(*reference_id),
+            sealed,
+            create_receive_account,
+            withdraw_nonce: u64::from_le_bytes(*withdraw_nonce),
+            dust_collector_pubkey: Pubkey::new_from_array(*dust_collector_pubkey),
+            registered,
+            reserved: *reserved,
         })
     }
 
@@ -58,7 +377,29 @@ impl Pack for Escrow {
             temp_token_account_pubkey_dst,
             withdrawer_pubkey_dst,
             deposited_amount_dst,
-        ) = mut_array_refs![dst, 1, 32, 32, 32, 8];
+            freeze_mode_dst,
+            reserve_amount_dst,
+            total_withdrawn_dst,
+            milestone_count_dst,
+            milestones_dst,
+            lamport_mode_dst,
+            full_only_dst,
+            authority_pubkey_dst,
+            dust_threshold_dst,
+            last_withdraw_unix_timestamp_dst,
+            withdraw_cooldown_secs_dst,
+            rent_to_treasury_dst,
+            require_receive_owner_dst,
+            hashlock_dst,
+            expiry_unix_timestamp_dst,
+            reference_id_dst,
+            sealed_dst,
+            create_receive_account_dst,
+            withdraw_nonce_dst,
+            dust_collector_pubkey_dst,
+            registered_dst,
+            reserved_dst,
+        ) = mut_array_refs![dst, 1, 32, 32, 32, 8, 1, 8, 8, 1, Milestone::LEN * MAX_MILESTONES, 1, 1, 32, 8, 8, 4, 1, 1, 32, 8, 8, 1, 1, 8, 32, 1, 202];
 
         let Escrow {
             is_initialized,
@@ -66,6 +407,28 @@ impl Pack for Escrow {
             temp_token_account_pubkey,
             withdrawer_pubkey,
             deposited_amount,
+            freeze_mode,
+            reserve_amount,
+            total_withdrawn,
+            milestone_count,
+            milestones,
+            lamport_mode,
+            full_only,
+            authority_pubkey,
+            dust_threshold,
+            last_withdraw_unix_timestamp,
+            withdraw_cooldown_secs,
+            rent_to_treasury,
+            require_receive_owner,
+            hashlock,
+            expiry_unix_timestamp,
+            reference_id,
+            sealed,
+            create_receive_account,
+            withdraw_nonce,
+            dust_collector_pubkey,
+            registered,
+            reserved,
         } = self;
 
         is_initialized_dst[0] = *is_initialized as u8;
@@ -74,6 +437,385 @@ impl Pack for Escrow {
         withdrawer_pubkey_dst
             .copy_from_slice(withdrawer_pubkey.as_ref());
         *deposited_amount_dst = deposited_amount.to_le_bytes();
+        freeze_mode_dst[0] = *freeze_mode as u8;
+        *reserve_amount_dst = reserve_amount.to_le_bytes();
+        *total_withdrawn_dst = total_withdrawn.to_le_bytes();
+        milestone_count_dst[0] = *milestone_count;
+        // slots past the count are written as zeroes so no stale schedule survives a repack
+        for (i, milestone) in milestones.iter().enumerate() {
+            let milestone = if i < *milestone_count as usize {
+                *milestone
+            } else {
+                Milestone::default()
+            };
+            milestone.pack_into_slice(array_mut_ref![
+                milestones_dst,
+                i * Milestone::LEN,
+                Milestone::LEN
+            ]);
+        }
+        lamport_mode_dst[0] = *lamport_mode as u8;
+        full_only_dst[0] = *full_only as u8;
+        authority_pubkey_dst.copy_from_slice(authority_pubkey.as_ref());
+        *dust_threshold_dst = dust_threshold.to_le_bytes();
+        *last_withdraw_unix_timestamp_dst = last_withdraw_unix_timestamp.to_le_bytes();
+        *withdraw_cooldown_secs_dst = withdraw_cooldown_secs.to_le_bytes();
+        rent_to_treasury_dst[0] = *rent_to_treasury as u8;
+        require_receive_owner_dst[0] = *require_receive_owner as u8;
+        *hashlock_dst = *hashlock;
+        *expiry_unix_timestamp_dst = expiry_unix_timestamp.to_le_bytes();
+        *reference_id_dst = reference_id.to_le_bytes();
+        sealed_dst[0] = *sealed as u8;
+        create_receive_account_dst[0] = *create_receive_account as u8;
+        *withdraw_nonce_dst = withdraw_nonce.to_le_bytes();
+        dust_collector_pubkey_dst.copy_from_slice(dust_collector_pubkey.as_ref());
+        registered_dst[0] = *registered as u8;
+        *reserved_dst = *reserved;
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_escrow() -> Escrow {
+        let mut milestones = [Milestone::default(); MAX_MILESTONES];
+        milestones[0] = Milestone {
+            unlock_unix_timestamp: 1_000,
+            fraction_bps: 4_000,
+        };
+        milestones[1] = Milestone {
+            unlock_unix_timestamp: 2_000,
+            fraction_bps: 6_000,
+        };
+
+        Escrow {
+            is_initialized: true,
+            initializer_pubkey: Pubkey::new_from_array([1; 32]),
+            temp_token_account_pubkey: Pubkey::new_from_array([2; 32]),
+            withdrawer_pubkey: Pubkey::new_from_array([3; 32]),
+            deposited_amount: 1_000,
+            freeze_mode: true,
+            reserve_amount: 100,
+            total_withdrawn: 250,
+            milestone_count: 2,
+            milestones,
+            lamport_mode: true,
+            full_only: true,
+            authority_pubkey: Pubkey::new_from_array([4; 32]),
+            dust_threshold: 5,
+            last_withdraw_unix_timestamp: 1_500,
+            withdraw_cooldown_secs: 60,
+            rent_to_treasury: true,
+            require_receive_owner: true,
+            hashlock: [5; 32],
+            expiry_unix_timestamp: 3_000,
+            reference_id: 42,
+            sealed: true,
+            create_receive_account: true,
+            withdraw_nonce: 7,
+            dust_collector_pubkey: Pubkey::new_from_array([6; 32]),
+            registered: true,
+            reserved: [0; 202],
+        }
+    }
+
+    fn packed(escrow: Escrow) -> Vec<u8> {
+        let mut data = vec![0; Escrow::LEN];
+        Escrow::pack(escrow, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn pack_round_trips_every_field() {
+        let data = packed(sample_escrow());
+        let escrow = Escrow::unpack(&data).unwrap();
+        let expected = sample_escrow();
+
+        assert!(escrow.is_initialized);
+        assert_eq!(escrow.initializer_pubkey, expected.initializer_pubkey);
+        assert_eq!(escrow.temp_token_account_pubkey, expected.temp_token_account_pubkey);
+        assert_eq!(escrow.withdrawer_pubkey, expected.withdrawer_pubkey);
+        assert_eq!(escrow.deposited_amount, expected.deposited_amount);
+        assert!(escrow.freeze_mode);
+        assert_eq!(escrow.reserve_amount, expected.reserve_amount);
+        assert_eq!(escrow.total_withdrawn, expected.total_withdrawn);
+        assert_eq!(escrow.milestone_count, expected.milestone_count);
+        assert_eq!(escrow.milestones, expected.milestones);
+        assert!(escrow.lamport_mode);
+        assert!(escrow.full_only);
+        assert_eq!(escrow.authority_pubkey, expected.authority_pubkey);
+        assert_eq!(escrow.dust_threshold, expected.dust_threshold);
+        assert_eq!(
+            escrow.last_withdraw_unix_timestamp,
+            expected.last_withdraw_unix_timestamp
+        );
+        assert_eq!(escrow.withdraw_cooldown_secs, expected.withdraw_cooldown_secs);
+        assert!(escrow.rent_to_treasury);
+        assert!(escrow.require_receive_owner);
+        assert_eq!(escrow.hashlock, expected.hashlock);
+        assert_eq!(escrow.expiry_unix_timestamp, expected.expiry_unix_timestamp);
+        assert_eq!(escrow.reference_id, expected.reference_id);
+        assert!(escrow.sealed);
+        assert!(escrow.create_receive_account);
+        assert_eq!(escrow.withdraw_nonce, expected.withdraw_nonce);
+        assert_eq!(escrow.dust_collector_pubkey, expected.dust_collector_pubkey);
+        assert!(escrow.registered);
+        assert_eq!(packed(escrow), data);
+    }
+
+    #[test]
+    fn pack_writes_fields_at_their_offsets() {
+        let data = packed(sample_escrow());
+
+        assert_eq!(data[0], 1);
+        assert_eq!(&data[1..33], &[1; 32]);
+        assert_eq!(&data[33..65], &[2; 32]);
+        assert_eq!(&data[65..97], &[3; 32]);
+        assert_eq!(&data[97..105], &1_000u64.to_le_bytes());
+        assert_eq!(data[105], 1);
+        assert_eq!(&data[106..114], &100u64.to_le_bytes());
+        assert_eq!(&data[114..122], &250u64.to_le_bytes());
+        assert_eq!(data[122], 2);
+        assert_eq!(&data[123..131], &1_000i64.to_le_bytes());
+        assert_eq!(&data[131..133], &4_000u16.to_le_bytes());
+        assert_eq!(data[163], 1);
+        assert_eq!(data[164], 1);
+        assert_eq!(&data[165..197], &[4; 32]);
+        assert_eq!(&data[197..205], &5u64.to_le_bytes());
+        assert_eq!(&data[205..213], &1_500i64.to_le_bytes());
+        assert_eq!(&data[213..217], &60u32.to_le_bytes());
+        assert_eq!(data[217], 1);
+        assert_eq!(data[218], 1);
+        assert_eq!(&data[219..251], &[5; 32]);
+        assert_eq!(&data[251..259], &3_000i64.to_le_bytes());
+        assert_eq!(&data[259..267], &42u64.to_le_bytes());
+        assert_eq!(data[267], 1);
+        assert_eq!(data[268], 1);
+        assert_eq!(&data[269..277], &7u64.to_le_bytes());
+        assert_eq!(&data[277..309], &[6; 32]);
+        assert_eq!(data[309], 1);
+        assert_eq!(&data[310..], &[0; 202]);
+    }
+
+    #[test]
+    fn unpack_gates_on_is_initialized() {
+        let data = [0; Escrow::LEN];
+
+        assert!(!Escrow::unpack_unchecked(&data).unwrap().is_initialized());
+        assert_eq!(
+            Escrow::unpack(&data).err(),
+            Some(ProgramError::UninitializedAccount)
+        );
+    }
+
+    #[test]
+    fn unpack_rejects_invalid_flags() {
+        for offset in [0, 105, 163, 164, 217, 218, 267, 268, 309] {
+            let mut data = packed(sample_escrow());
+            data[offset] = 2;
+            assert_eq!(
+                Escrow::unpack_unchecked(&data).err(),
+                Some(ProgramError::InvalidAccountData),
+                "flag at {}",
+                offset
+            );
+        }
+    }
+
+    #[test]
+    fn unpack_rejects_unknown_lengths() {
+        let data = packed(sample_escrow());
+
+        assert_eq!(
+            Escrow::unpack_unchecked(&data[..Escrow::LEN - 1]).err(),
+            Some(ProgramError::InvalidAccountData)
+        );
+        assert_eq!(
+            Escrow::unpack_unchecked(&data[..LEGACY_ESCROW_LEN + 1]).err(),
+            Some(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn legacy_escrows_read_with_a_zeroed_tail() {
+        let data = packed(sample_escrow());
+        let escrow = Escrow::unpack(&data[..LEGACY_ESCROW_LEN]).unwrap();
+
+        assert_eq!(escrow.deposited_amount, 1_000);
+        assert!(!escrow.freeze_mode);
+        assert_eq!(escrow.total_withdrawn, 0);
+        assert!(!escrow.full_only);
+        assert_eq!(escrow.withdraw_nonce, 0);
+    }
+
+    #[test]
+    fn legacy_escrows_only_store_the_original_fields() {
+        let data = packed(sample_escrow());
+        let mut legacy = data[..LEGACY_ESCROW_LEN].to_vec();
+
+        let mut escrow = Escrow::unpack(&legacy).unwrap();
+        escrow.deposited_amount = 400;
+        Escrow::pack(escrow, &mut legacy).unwrap();
+        assert_eq!(&legacy[97..105], &400u64.to_le_bytes());
+
+        let mut escrow = Escrow::unpack(&legacy).unwrap();
+        escrow.total_withdrawn = 600;
+        assert_eq!(
+            Escrow::pack(escrow, &mut legacy).err(),
+            Some(ProgramError::AccountDataTooSmall)
+        );
+        assert_eq!(&legacy[97..105], &400u64.to_le_bytes());
+    }
+
+    #[test]
+    fn claimable_amount_without_milestones_is_the_deposit() {
+        let mut escrow = sample_escrow();
+        escrow.milestone_count = 0;
+
+        assert_eq!(escrow.claimable_amount(0), 1_000);
+        assert_eq!(escrow.claimable_amount(i64::MAX), 1_000);
+    }
+
+    #[test]
+    fn claimable_amount_follows_the_schedule() {
+        let escrow = sample_escrow();
+
+        // 1_250 ever deposited, 250 of it already withdrawn
+        assert_eq!(escrow.claimable_amount(i64::MIN), 0);
+        assert_eq!(escrow.claimable_amount(999), 0);
+        assert_eq!(escrow.claimable_amount(1_000), 250);
+        assert_eq!(escrow.claimable_amount(1_999), 250);
+        assert_eq!(escrow.claimable_amount(2_000), 1_000);
+        assert_eq!(escrow.claimable_amount(i64::MAX), 1_000);
+    }
+
+    #[test]
+    fn claimable_amount_never_exceeds_the_deposit() {
+        let mut escrow = sample_escrow();
+        escrow.deposited_amount = u64::MAX;
+        escrow.total_withdrawn = u64::MAX;
+        escrow.milestones[1].fraction_bps = u16::MAX;
+
+        assert_eq!(escrow.claimable_amount(2_000), 0);
+
+        escrow.total_withdrawn = 0;
+        assert_eq!(escrow.claimable_amount(2_000), u64::MAX);
+    }
+
+    #[test]
+    fn reserved_padding_round_trips_at_the_end() {
+        let mut escrow = sample_escrow();
+        escrow.reserved = [9; 202];
+        let data = packed(escrow);
+
+        assert_eq!(Escrow::LEN, 512);
+        assert_eq!(&data[Escrow::LEN - 202..], &[9; 202]);
+        assert_eq!(Escrow::unpack(&data).unwrap().reserved, [9; 202]);
+    }
+
+    #[test]
+    fn rent_helpers_match_the_account_sizes() {
+        let rent = Rent::default();
+
+        assert_eq!(escrow_rent(&rent), rent.minimum_balance(Escrow::LEN));
+        assert_eq!(TEMP_TOKEN_ACCOUNT_LEN, 165);
+        assert_eq!(temp_token_account_rent(&rent), rent.minimum_balance(165));
+    }
+
+    #[test]
+    fn milestones_release_as_the_clock_passes_them() {
+        let mut escrow = sample_escrow();
+        escrow.deposited_amount = 1_000;
+        escrow.total_withdrawn = 0;
+
+        assert_eq!(escrow.claimable_amount(999), 0);
+
+        let first = escrow.claimable_amount(1_000);
+        assert_eq!(first, 400);
+        escrow.deposited_amount -= first;
+        escrow.total_withdrawn += first;
+        assert_eq!(escrow.claimable_amount(1_999), 0);
+
+        let second = escrow.claimable_amount(2_000);
+        assert_eq!(second, 600);
+        escrow.deposited_amount -= second;
+        escrow.total_withdrawn += second;
+        assert_eq!(escrow.claimable_amount(i64::MAX), 0);
+    }
+
+    #[test]
+    fn status_follows_the_escrow_lifecycle() {
+        let mut escrow = sample_escrow();
+        escrow.total_withdrawn = 0;
+        assert_eq!(escrow.status(0), EscrowStatus::Open);
+
+        escrow.total_withdrawn = 250;
+        assert_eq!(escrow.status(0), EscrowStatus::PartiallyWithdrawn);
+
+        assert_eq!(escrow.status(2_999), EscrowStatus::PartiallyWithdrawn);
+        assert_eq!(escrow.status(3_000), EscrowStatus::Expired);
+
+        escrow.deposited_amount = 0;
+        assert_eq!(escrow.status(0), EscrowStatus::Closed);
+        assert_eq!(escrow.status(3_000), EscrowStatus::Closed);
+
+        let uninitialized = Escrow::unpack_unchecked(&[0; Escrow::LEN]).unwrap();
+        assert_eq!(uninitialized.status(0), EscrowStatus::Closed);
+    }
+
+    #[test]
+    fn milestones_round_trip_at_every_fill_level() {
+        for count in [0, 2, MAX_MILESTONES] {
+            let mut escrow = sample_escrow();
+            escrow.milestone_count = count as u8;
+            for (i, milestone) in escrow.milestones.iter_mut().enumerate() {
+                *milestone = Milestone {
+                    unlock_unix_timestamp: 1_000 * (i as i64 + 1),
+                    fraction_bps: 2_500,
+                };
+            }
+            let data = packed(escrow);
+
+            let escrow = Escrow::unpack(&data).unwrap();
+            assert_eq!(escrow.milestone_count as usize, count);
+            for (i, milestone) in escrow.milestones.iter().enumerate() {
+                let expected = if i < count {
+                    Milestone {
+                        unlock_unix_timestamp: 1_000 * (i as i64 + 1),
+                        fraction_bps: 2_500,
+                    }
+                } else {
+                    // unused slots are zeroed on pack
+                    Milestone::default()
+                };
+                assert_eq!(*milestone, expected);
+            }
+            assert_eq!(packed(escrow), data);
+        }
+    }
+
+    #[test]
+    fn unpack_rejects_too_many_milestones() {
+        let mut data = packed(sample_escrow());
+        data[122] = MAX_MILESTONES as u8 + 1;
+
+        assert_eq!(
+            Escrow::unpack(&data).err(),
+            Some(EscrowError::TooManyEntries.into())
+        );
+    }
+
+    #[test]
+    fn state_hash_changes_after_a_partial_withdraw() {
+        let mut escrow = sample_escrow();
+        let before = escrow.state_hash();
+        assert_eq!(sample_escrow().state_hash(), before);
+
+        escrow.deposited_amount -= 100;
+        escrow.total_withdrawn += 100;
+        escrow.withdraw_nonce += 1;
+        assert_ne!(escrow.state_hash(), before);
+    }
+}
@@ -0,0 +1,116 @@
+//! Checks on SPL token accounts shared by the instruction handlers
+
+use solana_program::{
+    account_info::AccountInfo, entrypoint::ProgramResult, program_error::ProgramError,
+    program_pack::Pack, pubkey::Pubkey,
+};
+
+use spl_token::state::Account as TokenAccount;
+
+use crate::error::EscrowError;
+
+/// Checks that `account_info` is an initialized, unfrozen token account of the SPL token program
+/// and, when given, of `expected_mint` and owned by `expected_owner`.
+pub fn assert_usable_token_account(
+    account_info: &AccountInfo,
+    expected_mint: Option<&Pubkey>,
+    expected_owner: Option<&Pubkey>,
+) -> ProgramResult {
+    if *account_info.owner != spl_token::id() {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    let token_account = TokenAccount::unpack(&account_info.try_borrow_data()?)?;
+
+    if token_account.is_frozen() {
+        return Err(EscrowError::TokenAccountFrozen.into());
+    }
+
+    if expected_mint.is_some_and(|mint| token_account.mint != *mint)
+        || expected_owner.is_some_and(|owner| token_account.owner != *owner)
+    {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spl_token::state::AccountState;
+
+    fn token_account_data(mint: Pubkey, owner: Pubkey, state: AccountState) -> Vec<u8> {
+        let mut data = vec![0; TokenAccount::LEN];
+        TokenAccount::pack(
+            TokenAccount {
+                mint,
+                owner,
+                state,
+                ..TokenAccount::default()
+            },
+            &mut data,
+        )
+        .unwrap();
+        data
+    }
+
+    fn check(
+        data: &mut [u8],
+        program: &Pubkey,
+        expected_mint: Option<&Pubkey>,
+        expected_owner: Option<&Pubkey>,
+    ) -> ProgramResult {
+        let key = Pubkey::new_unique();
+        let mut lamports = 0;
+        let account_info =
+            AccountInfo::new(&key, false, false, &mut lamports, data, program, false, 0);
+        assert_usable_token_account(&account_info, expected_mint, expected_owner)
+    }
+
+    #[test]
+    fn accepts_a_matching_token_account() {
+        let (mint, owner) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut data = token_account_data(mint, owner, AccountState::Initialized);
+
+        assert_eq!(check(&mut data, &spl_token::id(), Some(&mint), Some(&owner)), Ok(()));
+        assert_eq!(check(&mut data, &spl_token::id(), None, None), Ok(()));
+    }
+
+    #[test]
+    fn rejects_a_frozen_token_account() {
+        let (mint, owner) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut data = token_account_data(mint, owner, AccountState::Frozen);
+
+        assert_eq!(
+            check(&mut data, &spl_token::id(), Some(&mint), Some(&owner)),
+            Err(EscrowError::TokenAccountFrozen.into())
+        );
+    }
+
+    #[test]
+    fn rejects_a_token_account_of_another_mint() {
+        let (mint, owner) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut data = token_account_data(mint, owner, AccountState::Initialized);
+
+        assert_eq!(
+            check(&mut data, &spl_token::id(), Some(&Pubkey::new_unique()), Some(&owner)),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn rejects_a_token_account_of_another_owner_or_program() {
+        let (mint, owner) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut data = token_account_data(mint, owner, AccountState::Initialized);
+
+        assert_eq!(
+            check(&mut data, &spl_token::id(), Some(&mint), Some(&Pubkey::new_unique())),
+            Err(ProgramError::InvalidAccountData)
+        );
+        assert_eq!(
+            check(&mut data, &Pubkey::new_unique(), Some(&mint), Some(&owner)),
+            Err(ProgramError::IncorrectProgramId)
+        );
+    }
+}